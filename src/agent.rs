@@ -6,11 +6,31 @@ use data::*;
 use num_cpus;
 use parse::{MsgParse, StatusParse};
 use std::cmp::{self, Ordering};
+use std::collections::HashMap;
 use std::fmt;
 use std::slice::Iter as SliceIter;
 use std::slice::IterMut as SliceIterMut;
 use std::str;
 
+// 見えなくなった起きている敵を追跡し続けるターン数
+const TRACK_TURN_MAX: usize = 30;
+// 見えない敵の位置を予測する最大の歩数
+const TRACK_STEP_MAX: i32 = 20;
+// 次の階の敵1体と戦うだけで最大HPのこれだけの割合を削られそうなら降りたくない
+const DEEP_DANGER_RATE: f64 = 0.5;
+// Xerocである確率がこれ以上なら、拾う前に物を投げて確かめる
+const MIMIC_PROBE_RATE: f64 = 0.02;
+// 見えない敵が攻撃してこなくなってから覚えておくターン数
+const UNSEEN_TURN_MAX: usize = 10;
+// さまよう敵は約70ターン後から4ターンごとに1/6で現れるので、平均94ターンに1体
+const WANDER_TURN: f64 = 94.0;
+// さまよう敵に削られるとしても、休む価値はこれ以下にしない
+const REST_RISK_MIN: f64 = 0.5;
+// 食べ物がないのに飢えるまで休む価値
+const STARVE_RATE: f64 = 0.2;
+// 拾った武器の命中補正の分布 10%で呪われて-1~-3、5%で+1~+3(rogueのnew_thing)
+const WEAPON_HPLUS: [(f64, i32); 3] = [(0.85, 0), (0.1, -2), (0.05, 2)];
+
 #[derive(Clone, Debug)]
 struct EnemyList(Vec<EnemyHist>);
 
@@ -22,8 +42,11 @@ impl EnemyList {
     fn from_vec(v: Vec<EnemyHist>) -> EnemyList {
         EnemyList(v)
     }
-    fn add(&mut self, enem: Enemy, cd: Coord) {
-        self.0.push(EnemyHist::new(enem, cd));
+    fn add(&mut self, enem: Enemy, cd: Coord, turn: usize) {
+        let mut hist = EnemyHist::new(enem, cd);
+        hist.last_seen = turn;
+        hist.hp_turn = turn;
+        self.0.push(hist);
     }
     fn all_invisible(&mut self) {
         for enem in self.iter_mut() {
@@ -46,9 +69,10 @@ impl EnemyList {
         }
         None
     }
-    fn get_around_mut(&mut self, cd: Coord, enem_arg: Enemy) -> Option<&mut EnemyHist> {
+    // enem_argがNoneなら種類を問わない(幻覚中など)
+    fn get_around_mut(&mut self, cd: Coord, enem_arg: Option<Enemy>) -> Option<&mut EnemyHist> {
         for enem in self.iter_mut() {
-            if enem.typ != enem_arg {
+            if enem_arg.is_some_and(|e| enem.typ != e) {
                 continue;
             }
             for &d in Direc::vars().take(8) {
@@ -72,18 +96,30 @@ impl EnemyList {
     fn iter_mut(&mut self) -> SliceIterMut<EnemyHist> {
         self.0.iter_mut()
     }
-    fn merge(&mut self, dangeon: &Dangeon) {
+    // 幻覚中は敵の文字がでたらめなので、位置と動きだけで追跡する
+    // 新しく見つけた敵の種類はguess(その階で出やすい敵)にしておく
+    fn merge(&mut self, dangeon: &Dangeon, guess: Option<Enemy>, turn: usize) {
+        let hallu = guess.is_some();
+        let player = dangeon.player_cd();
         self.all_invisible();
         for (cell_ref, cd) in dangeon.iter() {
             if let Some(enem) = cell_ref.enemy() {
                 macro_rules! exec_merge {
                     ($cd:ident, $res:ident, $run:expr) => {
                         if let Some(enem_hist) = self.get_mut($cd) {
-                            if enem_hist.typ == enem {
+                            if hallu || enem_hist.typ == enem {
+                                // 同じターンの画面(--More--など)を何度読んでも1回と数える
+                                let new_turn = enem_hist.last_seen != turn;
                                 enem_hist.cd = cd;
                                 enem_hist.visible = true;
+                                enem_hist.detected = false;
+                                enem_hist.last_seen = turn;
                                 if $run {
-                                    enem_hist.running = true;
+                                    enem_hist.wake();
+                                } else if new_turn
+                                    && player.is_none_or(|p| enem_hist.would_move(p))
+                                {
+                                    enem_hist.stayed();
                                 }
                                 $res = true;
                             }
@@ -96,7 +132,7 @@ impl EnemyList {
                     exec_merge!(search_cd, res, *direc != Direc::Stay);
                     res
                 });
-                if !merged && enem.has_attr(EnemyAttr::FLYING) {
+                if !merged && (hallu || enem.speed() >= 2) {
                     merged = Direc::vars().any(|direc| {
                         let mut res = false;
                         for &plus_cd in &[direc.to_cd(), direc.rotate().to_cd()] {
@@ -110,38 +146,118 @@ impl EnemyList {
                     });
                 }
                 if !merged {
-                    if let Some(i) = self.find_invisible(enem, cd) {
+                    if let Some(i) = self.find_invisible(enem, cd, hallu, dangeon, turn) {
                         self.0[i].cd = cd;
                         self.0[i].visible = true;
-                        self.0[i].running = true;
+                        self.0[i].detected = false;
+                        self.0[i].wake();
+                        self.0[i].last_seen = turn;
                     } else {
-                        self.add(enem, cd);
+                        self.add(guess.unwrap_or(enem), cd, turn);
                     }
                 }
             }
         }
+        // 画面に見えている敵はまだ倒れていない
+        for enem in self.iter_mut() {
+            enem.regenerate(turn);
+            if enem.visible {
+                enem.seen_alive();
+            }
+        }
+        self.drop_lost(turn);
+    }
+    // 敵の探知で写った敵は、見てはいないが位置の確かな敵として覚えておく
+    fn merge_detected(&mut self, orig: &[Vec<u8>], guess: Option<Enemy>, turn: usize) {
+        for (y, line) in orig.iter().enumerate() {
+            for (x, &c) in line.iter().enumerate() {
+                let cd = Coord::new(x as i32, y as i32);
+                if !c.is_ascii_uppercase() || self.get(cd).is_some() {
+                    continue;
+                }
+                self.add(guess.unwrap_or_else(|| Enemy::from(c)), cd, turn);
+                if let Some(enem) = self.get_mut(cd) {
+                    enem.visible = false;
+                    enem.detected = true;
+                }
+            }
+        }
     }
-    fn find_invisible(&self, enem_t: Enemy, cd: Coord) -> Option<usize> {
-        let mut aim = (10000, EucDist(7000.0));
-        for (i, enem) in self.iter().enumerate() {
-            if enem.typ == enem_t && !enem.visible {
-                let dist = enem.cd.dist_euc(&cd);
-                if dist < aim.1 {
-                    aim = (i, dist);
+    // 見えなくなった敵のうち、今cdにいてもおかしくないものを優先して選ぶ
+    fn find_invisible(
+        &self,
+        enem_t: Enemy,
+        cd: Coord,
+        hallu: bool,
+        dangeon: &Dangeon,
+        turn: usize,
+    ) -> Option<usize> {
+        self.iter()
+            .enumerate()
+            .filter(|&(_, enem)| (hallu || enem.typ == enem_t) && !enem.visible)
+            .min_by_key(|&(_, enem)| {
+                let reachable = EnemyList::predict(enem, dangeon, turn).contains(&cd);
+                (!reachable, enem.cd.dist_euc(&cd))
+            })
+            .map(|(i, _)| i)
+    }
+    // 見えない敵が今いる可能性のあるマス
+    fn predict(enem: &EnemyHist, dangeon: &Dangeon, turn: usize) -> Vec<Coord> {
+        if !enem.running {
+            return vec![enem.cd];
+        }
+        let elapsed = turn.saturating_sub(enem.last_seen) as i32;
+        let steps = cmp::min(elapsed * enem.typ.speed(), TRACK_STEP_MAX);
+        dangeon.enemy_reach(enem.cd, steps)
+    }
+    // 長い間見えていない起きている敵は、どこにいるか分からないので忘れる
+    fn drop_lost(&mut self, turn: usize) {
+        self.0.retain(|enem| {
+            enem.visible || enem.detected || !enem.running
+                || turn.saturating_sub(enem.last_seen) <= TRACK_TURN_MAX
+        });
+    }
+    // 見えない起きている敵がそのマスにいる確率の和
+    fn danger_map(&self, dangeon: &Dangeon, turn: usize) -> SimpleMap<f64> {
+        let mut res = SimpleMap::new(0.0);
+        for enem in self.iter().filter(|e| !e.visible && e.running) {
+            let cands = EnemyList::predict(enem, dangeon, turn);
+            let prob = 1.0 / cands.len() as f64;
+            for cd in cands {
+                if let Some(p) = res.get_mut(cd) {
+                    *p += prob;
                 }
             }
         }
-        if aim.0 < 10000 {
-            Some(aim.0)
-        } else {
-            None
+        res
+    }
+    // 部屋に入ったり隣に来たりすると寝ている敵が起きるかもしれない
+    fn player_moved(&mut self, dangeon: &Dangeon, prev: Coord, cur: Coord) {
+        let from_door = dangeon.get(prev).is_some_and(|c| c.surface() == Surface::Door);
+        let in_room = dangeon
+            .get(cur)
+            .is_some_and(|c| c.surface() != Surface::Door && c.surface() != Surface::Road);
+        let entered = from_door && in_room;
+        for enem in self.iter_mut().filter(|e| e.visible && !e.running) {
+            if (entered && enem.cd.block() == cur.block()) || enem.cd.dist_cheb(&cur) <= 1 {
+                enem.disturbed();
+            }
         }
     }
+    // 探索で動きを読む敵(見えているか、寝ていて位置が分かっているもの)
+    fn for_search(&self) -> EnemyList {
+        EnemyList::from_vec(
+            self.iter()
+                .filter(|e| e.visible || !e.running)
+                .cloned()
+                .collect(),
+        )
+    }
 
-    fn remove(&mut self, cd: Coord, target: Enemy) -> bool {
+    fn remove(&mut self, cd: Coord, target: Option<Enemy>) -> bool {
         let mut rem_id = None;
         for (i, enem) in self.iter().enumerate() {
-            if enem.cd == cd && enem.typ == target {
+            if enem.cd == cd && !target.is_some_and(|t| enem.typ != t) {
                 rem_id = Some(i);
                 break;
             }
@@ -154,11 +270,93 @@ impl EnemyList {
         }
     }
 
+    // 幻覚が終わったら見えている敵の種類を付け直す
+    fn relabel(&mut self, dangeon: &Dangeon) {
+        for enem in self.iter_mut().filter(|e| e.visible) {
+            if let Some(typ) = dangeon.get(enem.cd).and_then(|c| c.enemy()) {
+                if typ != enem.typ {
+                    enem.retype(typ);
+                }
+            }
+        }
+    }
+
     fn coord_list(&self) -> Vec<Coord> {
         self.iter().map(|enem| enem.cd).collect()
     }
 }
 
+// 姿の見えない敵(Phantomや目が見えない時の敵)
+// 位置が分からないので、いそうなマスを確率で持つ
+#[derive(Clone, Debug)]
+struct Unseen {
+    hist: EnemyHist,
+    cands: Vec<(Coord, f64)>,
+}
+
+impl Unseen {
+    fn new(typ: Enemy, around: &[Coord], turn: usize) -> Unseen {
+        let mut hist = EnemyHist::new(typ, Coord::default());
+        hist.wake();
+        hist.visible = false;
+        let mut res = Unseen {
+            hist,
+            cands: Vec::new(),
+        };
+        res.attacked(around, turn);
+        res
+    }
+    // 攻撃してきたので今は隣のどこかにいる
+    // 前にいそうだったマスから1歩で来られる所ほどありそう
+    fn attacked(&mut self, around: &[Coord], turn: usize) {
+        self.hist.last_seen = turn;
+        let weight = |cd: Coord| -> f64 {
+            self.cands
+                .iter()
+                .filter(|&&(c, _)| c.dist_cheb(&cd) <= 1)
+                .map(|&(_, p)| p)
+                .sum()
+        };
+        let mut cands: Vec<_> = around.iter().map(|&cd| (cd, weight(cd))).collect();
+        if cands.iter().all(|&(_, p)| p <= 0.0) {
+            for cand in &mut cands {
+                cand.1 = 1.0;
+            }
+        }
+        self.cands = cands;
+        self.normalize();
+    }
+    // こちらの攻撃が当たった(外れた)ので位置が分かった
+    fn found(&mut self, cd: Coord) {
+        self.cands = vec![(cd, 1.0)];
+    }
+    // そのマスに入れたのでそこにはいない
+    fn not_there(&mut self, cd: Coord) {
+        self.cands.retain(|&(c, _)| c != cd);
+        self.normalize();
+    }
+    fn normalize(&mut self) {
+        let sum: f64 = self.cands.iter().map(|&(_, p)| p).sum();
+        if sum > 0.0 {
+            for cand in &mut self.cands {
+                cand.1 /= sum;
+            }
+        }
+    }
+    fn is_lost(&self, turn: usize) -> bool {
+        self.cands.is_empty() || turn.saturating_sub(self.hist.last_seen) > UNSEEN_TURN_MAX
+    }
+    // 一番いそうなマスにいるものとして探索に渡す
+    fn likely(&self) -> Option<EnemyHist> {
+        let &(cd, _) = self.cands
+            .iter()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())?;
+        let mut hist = self.hist.clone();
+        hist.cd = cd;
+        Some(hist)
+    }
+}
+
 struct ItemList(Vec<ItemPack>);
 
 impl ItemList {
@@ -168,10 +366,16 @@ impl ItemList {
         let mut arm = ItemPack::new(b'b', "", 1, Item::Armor(Armor::Ring));
         arm.val = Some(4);
         res.merge(arm);
-        res.merge(ItemPack::new(b'c', "", 1, Item::Weapon(Weapon::Mace)));
-        res.merge(ItemPack::new(b'd', "", 1, Item::Weapon(Weapon::Bow)));
+        let mut mace = ItemPack::new(b'c', "", 1, Item::Weapon(Weapon::Mace));
+        mace.plus = Some((1, 1));
+        res.merge(mace);
+        let mut bow = ItemPack::new(b'd', "", 1, Item::Weapon(Weapon::Bow));
+        bow.plus = Some((1, 0));
+        res.merge(bow);
         // Arrowの数は少なめに見つもっておく(どうせ拾った時わかるから)
-        res.merge(ItemPack::new(b'e', "", 20, Item::Weapon(Weapon::Arrow)));
+        let mut arrow = ItemPack::new(b'e', "", 20, Item::Weapon(Weapon::Arrow));
+        arrow.plus = Some((0, 0));
+        res.merge(arrow);
         res
     }
     fn merge(&mut self, i: ItemPack) -> bool {
@@ -215,6 +419,12 @@ impl ItemList {
             None
         }
     }
+    // 補正が分からない武器は+0とみなす
+    fn get_attack(&self, id: u8, throw: bool) -> Option<Attack> {
+        let w = self.get_weapon(id)?;
+        let plus = self.get(id)?.plus.unwrap_or_default();
+        Some(Attack::new(w, plus, throw))
+    }
     fn any_food(&self) -> Option<u8> {
         for it in self.iter() {
             if let Item::Food(_) = it.typ {
@@ -244,6 +454,12 @@ impl ItemList {
     fn iter(&self) -> SliceIter<ItemPack> {
         self.0.iter()
     }
+    // 持ち物にないと分かったものを消す
+    fn forget(&mut self, id: u8) {
+        if let Some(item) = self.get_mut(id) {
+            *item = ItemPack::default();
+        }
+    }
 }
 
 struct ItemCall(i64);
@@ -277,6 +493,10 @@ impl Ord for ActionVal {
     }
 }
 
+// 最大HPが倍になるだけの経験値の価値
+// レベル1(最大HP12)で1レベル上がると約200になる
+const EXP_VAL_SCALE: f64 = 440.0;
+
 impl ActionVal {
     fn from_gold(i: i32) -> ActionVal {
         ActionVal(f64::from(i))
@@ -289,8 +509,29 @@ impl ActionVal {
         let base = d / cmp::max(hp_exp, DamageVal::half());
         ActionVal(*base * 10.0)
     }
-    fn from_exp(i: i32) -> ActionVal {
-        ActionVal(f64::from(i * 20))
+    // 経験値はレベルが上がって最大HPが増えるぶんだけ価値がある
+    // 最大HPが少ないうちほど1レベルの重みが大きい
+    fn from_exp(stat: &PlayerStatus, i: i32) -> ActionVal {
+        let levels = stat.level_progress(i);
+        let max_hp = f64::from(cmp::max(stat.max_hp, 1));
+        let gain = stat.max_hp_after(levels) - max_hp;
+        ActionVal(EXP_VAL_SCALE * gain / max_hp)
+    }
+    fn from_kill(stat: &PlayerStatus, enem: Enemy, prob: f64) -> ActionVal {
+        let val = ActionVal::from_gold(enem.treasure()) + ActionVal::from_exp(stat, enem.exp());
+        ActionVal(*val * prob)
+    }
+    fn from_fight(stat: &PlayerStatus, enem: Enemy, res: FightResult) -> ActionVal {
+        ActionVal::from_kill(stat, enem, res.win) + ActionVal(*ActionVal::death() * res.death)
+    }
+    // 敵1体から受けるダメージが減るぶん この先の何体もの敵に効く
+    fn from_weapon(stat: &PlayerStatus, saved: f64) -> ActionVal {
+        let max_hp = f64::from(cmp::max(stat.max_hp, 1));
+        ActionVal(400.0 * saved / max_hp)
+    }
+    // Armが1上がるごと
+    fn from_armor(gain: i32) -> ActionVal {
+        ActionVal(f64::from(gain) * 20.0)
     }
     fn from_hung(hung: i8) -> ActionVal {
         match hung {
@@ -310,6 +551,7 @@ impl ActionVal {
             Item::Gold => 25.0,
             Item::Ring => 10.0,
             Item::Amulet => 100.0,
+            Item::Magic => 14.0,
             Item::None => 0.0,
         })
     }
@@ -319,17 +561,11 @@ impl ActionVal {
     // searchコマンドに対する評価値
     pub fn search(nr_areas: u8) -> ActionVal {
         ActionVal(match nr_areas {
+            0 => 1.0,
             1 => 3.0,
-            2 => 10.0,
-            _ => 1.0,
+            _ => 10.0,
         })
     }
-    // ActionValに対し探索成功確率で補正をかける
-    pub fn comp_suc_rate(self, rate: f64) -> ActionVal {
-        const PARTIRION_RATE: f64 = 0.3;
-        let val = *self * PARTIRION_RATE + *self * (1.0 - PARTIRION_RATE) * rate;
-        ActionVal(val)
-    }
     // ActionValに対し探索の深さで補正をかける
     pub fn comp_search_depth(self, turn: usize) -> ActionVal {
         const BASE: f64 = enemy_search::SEARCH_DEPTH_MAX as f64;
@@ -349,16 +585,23 @@ impl ActionVal {
         self * comp / ActionVal(div)
     }
     // TODO: Magic Numberを使わないで書く
-    fn stair(exp_rate: f64) -> ActionVal {
+    // 次の階が危ないなら、今の階をもっと探索して経験値を稼ぐ
+    fn stair(exp_rate: f64, too_deep: bool) -> ActionVal {
         let comp = 1.0 - exp_rate.log(2.0) / (-5.0);
-        ActionVal(20.0 * comp)
+        let rate = if too_deep { 0.5 } else { 1.0 };
+        ActionVal(20.0 * comp * rate)
     }
-    fn recover(enough_hp: bool) -> ActionVal {
-        ActionVal(if enough_hp { 0.0 } else { 30.0 })
+    // rateは休むことの割に合う度合い(0~1)
+    fn recover(rate: f64) -> ActionVal {
+        ActionVal(30.0 * rate)
     }
     fn death() -> ActionVal {
         -ActionVal(1000.0)
     }
+    // 見えない敵がいそうなマスに入る
+    fn danger(prob: f64) -> ActionVal {
+        -ActionVal(prob * 20.0)
+    }
 }
 
 #[macro_export]
@@ -390,6 +633,7 @@ struct PlayInfo {
     dest: Option<Coord>,
     priority: ActionVal,
     tact: Tactics,
+    search_left: u32, // Tactics::Searchで残りのsearch回数
 }
 
 impl PlayInfo {
@@ -397,6 +641,7 @@ impl PlayInfo {
         self.tact = Tactics::None;
         self.dest = None;
         self.priority = ActionVal::default();
+        self.search_left = 0;
     }
     // 自分の座標以外は行動決定時に更新する
     fn update(&self, tact: Tactics, act: Action, dest: Option<Coord>, val: ActionVal) -> PlayInfo {
@@ -405,6 +650,7 @@ impl PlayInfo {
         res.act = act;
         res.dest = dest;
         res.priority = val;
+        res.search_left = 0;
         res
     }
     // actionのみupdateする(recoverなど)
@@ -413,15 +659,13 @@ impl PlayInfo {
         res.act = act;
         res
     }
-    // actionのみupdateして、ついでにinitする(searchなど)
-    fn update_act_init(&self, act: Action) -> PlayInfo {
-        let mut res = self.clone();
-        res.act = act;
-        res.tact = Tactics::None;
-        res.dest = None;
-        res.priority = ActionVal::default();
-        res
-    }
+}
+
+// テレポートの罠や巻物、落とし穴による移動
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Jump {
+    Teleport(Coord, Coord), // 移動元と移動先
+    Trapdoor(i8),           // 落ちた先の階
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -430,6 +674,9 @@ struct MsgFLags {
     new_cd: Option<Coord>, // 移動したかどうか
     invalid_item: bool,    // invalid itemを使用したかどうか
     invalid_stair: bool,   // invalid stair
+    picked: bool,          // アイテムを拾ったかどうか
+    new_stage: bool,       // 階が変わったかどうか
+    sober: bool,           // 幻覚が終わったかどうか
 }
 
 impl MsgFLags {
@@ -438,6 +685,8 @@ impl MsgFLags {
             GameMsg::NotValid => self.invalid_item = true,
             GameMsg::NoStair => self.invalid_stair = true,
             GameMsg::Defeated(_) => self.defeated = true,
+            GameMsg::Item(_) => self.picked = true,
+            GameMsg::Hallucinate(false) => self.sober = true,
             _ => {}
         }
     }
@@ -481,6 +730,15 @@ pub struct FeudalAgent {
     item_call: ItemCall,
     msg_flags: MsgFLags,
     equipment: Equipment,
+    route: RoutePlan,
+    hallucinating: bool,
+    probed: Vec<Coord>, // Xerocでないか確かめたアイテムの位置
+    unseen: Option<Unseen>,
+    missile: Option<(Coord, Weapon)>, // 投げた武器が落ちたはずのマス
+    food: (i32, usize),               // 最後に食べた直後の満腹度とそのターン
+    cursed: Vec<u8>,                  // 呪われていて外せなかった武器と鎧
+    turn: usize,      // action()が呼ばれた回数(--More--や待ち時間でも増える)
+    game_turn: usize, // ゲーム内で経過したターン数(行動を送った回数)
     dead: bool,
 }
 
@@ -506,24 +764,190 @@ impl FeudalAgent {
             item_call: ItemCall(0),
             msg_flags: MsgFLags::default(),
             equipment: Equipment::initial(),
+            route: RoutePlan::default(),
+            hallucinating: false,
+            probed: Vec::new(),
+            unseen: None,
+            missile: None,
+            food: (FOOD_RATION, 0),
+            cursed: Vec::new(),
+            turn: 0,
+            game_turn: 0,
             dead: false,
         }
     }
-    fn cur_weapon(&self) -> Option<Weapon> {
-        let id = self.equipment.weapon_id?;
-        self.item_list.get_weapon(id)
-    }
-    fn throw_weapon(&self) -> Vec<(Weapon, u32)> {
+    fn throw_weapon(&self) -> Vec<(Attack, u32)> {
         let mut res = Vec::new();
         for ip in self.item_list.iter() {
             if let Item::Weapon(w) = ip.typ {
                 if w.has_attr(WeaponAttr::MISL) {
-                    res.push((w, ip.num));
+                    res.push((Attack::new(w, ip.plus.unwrap_or_default(), true), ip.num));
                 }
             }
         }
         res
     }
+    // 今の装備で殴ったり投げたりしたときの補正
+    fn combat_profile(&self) -> CombatProfile {
+        let mut res = CombatProfile::new(&self.player_stat, Weapon::None);
+        if let Some(atk) = self.equipment.weapon_id.and_then(|id| self.item_list.get_attack(id, false)) {
+            res.wield = atk;
+        }
+        for item in self.worn_rings() {
+            let n = item.val.unwrap_or_default();
+            match item.name.as_str() {
+                "dexterity" => res.ring_plus.0 += n,
+                "increase damage" => res.ring_plus.1 += n,
+                _ => {}
+            }
+        }
+        res
+    }
+    fn worn_rings(&self) -> Vec<&ItemPack> {
+        let rings = [self.equipment.rring_id, self.equipment.lring_id];
+        rings.iter().filter_map(|id| self.item_list.get((*id)?)).collect()
+    }
+    fn ring_count(&self, name: &str) -> i32 {
+        self.worn_rings().iter().filter(|item| item.name == name).count() as i32
+    }
+    // その鎧を着たときに表示されるArm 補正が分からないものは+0とみなす
+    fn armor_arm(&self, id: Option<u8>) -> i32 {
+        let armor = id.and_then(|id| self.item_list.get(id)).and_then(|item| match item.typ {
+            Item::Armor(a) => Some(item.val.unwrap_or(10 - a.class())),
+            _ => None,
+        });
+        let rings: i32 = self.worn_rings()
+            .iter()
+            .filter(|item| item.name == "protection")
+            .map(|item| item.val.unwrap_or_default())
+            .sum();
+        armor.unwrap_or_default() + rings
+    }
+    // 今より良い鎧を拾っていたら着替える 着ている鎧はまず脱ぐ
+    fn change_armor(&self) -> Option<(Action, i32)> {
+        let cur_id = self.equipment.armor_id;
+        let cur = self.armor_arm(cur_id);
+        let (id, arm) = self.item_list
+            .iter()
+            .filter(|ip| ip.num > 0 && Some(ip.id) != cur_id)
+            .filter_map(|ip| match ip.typ {
+                Item::Armor(_) => Some((ip.id, self.armor_arm(Some(ip.id)))),
+                _ => None,
+            })
+            .max_by_key(|&(_, arm)| arm)?;
+        if arm <= cur {
+            return None;
+        }
+        match cur_id {
+            Some(cur_id) if self.cursed.contains(&cur_id) => None,
+            Some(_) => Some((Action::TakeArmorOff, arm - cur)),
+            None => Some((Action::WearArmor(id), arm - cur)),
+        }
+    }
+    // 着た鎧の補正はステータスのArmから分かる(錆びて下がった場合も)
+    fn learn_armor(&mut self) {
+        let id = match self.equipment.armor_id {
+            Some(id) => id,
+            None => return,
+        };
+        // 補正の分からない守りの指輪をはめていると鎧の補正と区別できない
+        let unknown_ring = self.worn_rings()
+            .iter()
+            .any(|item| item.name == "protection" && item.val.is_none());
+        if unknown_ring {
+            return;
+        }
+        let rings = self.armor_arm(None);
+        let arm = i32::from(self.player_stat.arm) - rings;
+        if let Some(item) = self.item_list.get_mut(id) {
+            item.val = Some(arm);
+        }
+    }
+    // その武器を持ってこの階の敵1体と戦ったときに受けるダメージ
+    // 補正が分からない武器は呪われているかもしれない
+    fn wield_danger(&self, id: Option<u8>) -> f64 {
+        let mut profile = self.combat_profile();
+        let level = i32::from(self.player_stat.stage_level);
+        let atk = id.and_then(|id| self.item_list.get_attack(id, false));
+        let known = id.and_then(|id| self.item_list.get(id)).is_none_or(|ip| ip.plus.is_some());
+        let atk = atk.unwrap_or_else(|| Attack::new(Weapon::None, (0, 0), false));
+        let mut danger = |plus| {
+            profile.wield = Attack::new(atk.weapon, plus, false);
+            *level_danger(&profile, level)
+        };
+        if known {
+            danger(atk.plus)
+        } else {
+            WEAPON_HPLUS.iter().map(|&(p, h)| p * danger((h, 0))).sum()
+        }
+    }
+    // 今より良い近接武器を拾っていたら持ち替える
+    // rogue 5.4.4のinit_damではtwo handed swordは4d4(投げると1d2)で、init_weaponは
+    // どの武器も+0,+0で作るので、両手持ちだからといって命中やダメージが下がることはない
+    fn change_weapon(&self) -> Option<(Action, f64)> {
+        let cur_id = self.equipment.weapon_id;
+        if cur_id.is_some_and(|id| self.cursed.contains(&id)) {
+            return None;
+        }
+        let cur = self.wield_danger(cur_id);
+        let (id, danger) = self.item_list
+            .iter()
+            .filter(|ip| ip.num > 0 && Some(ip.id) != cur_id)
+            .filter_map(|ip| match ip.typ {
+                Item::Weapon(w) if !w.has_attr(WeaponAttr::MANY) => {
+                    Some((ip.id, self.wield_danger(Some(ip.id))))
+                }
+                _ => None,
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())?;
+        if danger < cur {
+            Some((Action::WieldWeapon(id), cur - danger))
+        } else {
+            None
+        }
+    }
+    // 持ち物はコマンドと一緒に送っているので、まだ聞かれているなら受け付けられなかった
+    // 同じ答えを送っても繰り返すだけなので、その持ち物は忘れてやめる
+    fn item_rejected(&mut self) -> Vec<u8> {
+        if let Some(id) = self.play_info.act.item_id() {
+            self.item_list.forget(id);
+        }
+        self.msg_flags.invalid_item = true;
+        Action::Escape.into()
+    }
+    // 持ち替えたり脱いだりしようとした装備が呪われていた
+    fn found_cursed(&mut self) {
+        let id = match self.play_info.act {
+            Action::WieldWeapon(_) => self.equipment.weapon_id,
+            Action::TakeArmorOff => self.equipment.armor_id,
+            _ => None,
+        };
+        self.cursed.extend(id);
+    }
+    fn food_left(&self) -> i32 {
+        let (ate, turn) = self.food;
+        let rings_eat = self.worn_rings().iter().map(|item| ring_eat(&item.name)).sum();
+        self.player_stat.food_left(ate, self.game_turn.saturating_sub(turn), rings_eat)
+    }
+    // 休んで回復する価値
+    // 回復に時間がかかるほどさまよう敵に出会いやすく、食べ物がなければ飢えてしまう
+    fn recover_val(&self) -> ActionVal {
+        let stat = &self.player_stat;
+        if stat.have_enough_hp() {
+            return ActionVal::default();
+        }
+        let rings = self.ring_count("regeneration");
+        let target = stat.enough_hp();
+        let turns = stat.turns_to_hp(target, rings);
+        let gain = f64::from(target - stat.cur_hp);
+        let level = i32::from(stat.stage_level);
+        let wander = turns as f64 / WANDER_TURN * *level_danger(&self.combat_profile(), level);
+        let mut rate = (1.0 - wander / gain).max(REST_RISK_MIN);
+        if self.item_list.any_food().is_none() && turns as i32 >= self.food_left() {
+            rate *= STARVE_RATE;
+        }
+        ActionVal::recover(rate)
+    }
     fn get_weapon_id(&self, w1: Weapon) -> Option<u8> {
         for ip in self.item_list.iter() {
             if let Item::Weapon(w2) = ip.typ {
@@ -534,6 +958,119 @@ impl FeudalAgent {
         }
         None
     }
+    // 幻覚中はメッセージの敵の名前もでたらめ
+    fn msg_enemy(&self, enem: Enemy) -> Option<Enemy> {
+        if self.hallucinating {
+            None
+        } else {
+            Some(enem)
+        }
+    }
+    // 幻覚中は敵の種類が分からないので、その階で出やすい敵だと思っておく
+    fn guess_enemy(&self) -> Enemy {
+        likely_enemy(&Enemy::all(), i32::from(self.player_stat.stage_level))
+    }
+    // そのアイテムが実はXerocである確率
+    fn mimic_risk(&self, cd: Coord) -> f64 {
+        // 探知で見つけたものは本物
+        let detected = self.dangeon.get(cd).is_some_and(|cell| cell.is_detected());
+        if detected || self.probed.contains(&cd) {
+            0.0
+        } else {
+            mimic_prob(i32::from(self.player_stat.stage_level))
+        }
+    }
+    // Xerocかもしれないアイテムには、踏む前に遠くから何か投げて確かめる
+    fn probe_mimic(&mut self, item_cd: Coord) -> Option<Action> {
+        if self.mimic_risk(item_cd) < MIMIC_PROBE_RATE {
+            return None;
+        }
+        let cur_cd = self.play_info.cd;
+        let diff = item_cd - cur_cd;
+        let straight = diff.x == 0 || diff.y == 0 || diff.x.abs() == diff.y.abs();
+        if !straight || cur_cd.dist_cheb(&item_cd) < 2 {
+            return None;
+        }
+        let unit = Coord {
+            x: diff.x.signum(),
+            y: diff.y.signum(),
+        };
+        let d = *Direc::vars().find(|d| d.to_cd() == unit)?;
+        for cd in cur_cd.direc_iter(d)?.skip(1).take_while(|&cd| cd != item_cd) {
+            let cell = self.dangeon.get(cd)?;
+            match cell.surface() {
+                Surface::Wall | Surface::None | Surface::Door => return None,
+                _ => {}
+            }
+            if self.enemy_list.get(cd).is_some() {
+                return None;
+            }
+        }
+        let atk = enemy_search::select_throw(&self.combat_profile(), &self.throw_weapon())?;
+        let id = self.get_weapon_id(atk.weapon)?;
+        self.probed.push(item_cd);
+        Some(Action::Throw((d, id)))
+    }
+    // 正体を現したXerocのいる位置
+    fn mimic_cd(&self) -> Option<Coord> {
+        let cur_cd = self.play_info.cd;
+        match self.play_info.act {
+            Action::Move(d) | Action::Fight(d) => Some(cur_cd + d.to_cd()),
+            Action::Throw((d, _)) => cur_cd
+                .direc_iter(d)?
+                .find(|&cd| self.dangeon.get(cd).and_then(|cell| cell.memory()).is_some()),
+            _ => None,
+        }
+    }
+    // 投げた武器が落ちるマス 敵がいればその位置、いなければ壁の手前
+    fn missile_land(&self, d: Direc) -> Option<Coord> {
+        let mut land = None;
+        for cd in self.play_info.cd.direc_iter(d)?.skip(1) {
+            match self.dangeon.get(cd)?.surface() {
+                Surface::Wall | Surface::None | Surface::Door => break,
+                _ => {}
+            }
+            land = Some(cd);
+            if self.enemy_list.get(cd).is_some() {
+                break;
+            }
+        }
+        land
+    }
+    // 持ち替える候補 矢のある弓と、一番強い近接武器
+    fn wield_cands(&self) -> Vec<Attack> {
+        if self.equipment.weapon_id.is_some_and(|id| self.cursed.contains(&id)) {
+            return Vec::new();
+        }
+        let profile = self.combat_profile();
+        let launchers: Vec<_> = self.throw_weapon()
+            .into_iter()
+            .filter(|&(_, n)| n > 0)
+            .filter_map(|(atk, _)| atk.weapon.launcher())
+            .collect();
+        let mut res = Vec::new();
+        let mut melee: Option<Attack> = None;
+        for ip in self.item_list.iter().filter(|ip| ip.num > 0) {
+            let atk = match self.item_list.get_attack(ip.id, false) {
+                Some(atk) => atk,
+                None => continue,
+            };
+            if launchers.contains(&atk.weapon) {
+                res.push(atk);
+            } else if !atk.weapon.has_attr(WeaponAttr::MANY)
+                && melee.is_none_or(|m| profile.expect_dam(m) < profile.expect_dam(atk))
+            {
+                melee = Some(atk);
+            }
+        }
+        res.extend(melee);
+        res
+    }
+    fn too_deep(&self) -> bool {
+        let stat = &self.player_stat;
+        let danger = level_danger(&self.combat_profile(), i32::from(stat.stage_level) + 1);
+        *danger > f64::from(stat.max_hp) * DEEP_DANGER_RATE
+    }
     fn is_dest(&self) -> bool {
         if let Some(cd) = self.play_info.dest {
             self.play_info.cd == cd
@@ -543,7 +1080,11 @@ impl FeudalAgent {
     }
     fn next_stage(&mut self) {
         self.enemy_list.init();
+        self.probed.clear();
+        self.unseen = None;
+        self.missile = None;
         self.dangeon.init();
+        self.route.init();
         self.play_info.init_tact();
     }
     // 食糧・敵への対処など優先度の高い処理
@@ -569,7 +1110,9 @@ impl FeudalAgent {
         }
     }
     fn move_to_dest_sub(&self, dest: Coord) -> Option<Direc> {
-        let dist = self.dangeon.make_dist_map(dest)?;
+        // 見えない敵が待ち構えていそうな通路はなるべく避ける
+        let danger = self.enemy_list.danger_map(&self.dangeon, self.game_turn);
+        let dist = self.dangeon.make_cost_map(dest, &danger)?;
         let cd = self.play_info.cd;
         let cur_dist = *dist.get(cd)?;
         let mut max_diff = 0;
@@ -598,24 +1141,40 @@ impl FeudalAgent {
         // 敵→もう書いた
         // Explore, PickItem, Recover, Tostair が必要
         let (fight_val, fight_act) = enemy_search::exec(self).unwrap_or_default();
-        let recover_val = ActionVal::recover(self.player_stat.have_enough_hp());
+        let recover_val = self.recover_val();
 
         let cur_cd = self.play_info.cd;
         let dist = self.dangeon.make_dist_map(cur_cd)?;
 
         let (item_cd, item_val) = self.dangeon.find_nearest_item(&dist).unwrap_or_default();
-        let (explore_cd, explore_val) = self.dangeon.explore(&dist).unwrap_or_default();
+        let item_val = ActionVal(*item_val * (1.0 - self.mimic_risk(item_cd)));
+        self.route.update(&self.dangeon, cur_cd, &dist);
+        let (explore_cd, explore_val) = self.dangeon
+            .explore(&dist, &self.route)
+            .unwrap_or_default();
         let (stair_cd, stair_val) = if let Some(stair_cd) = self.dangeon.find_stair() {
             let exp_rate = self.dangeon.explore_rate();
-            (stair_cd, ActionVal::stair(*exp_rate))
+            (stair_cd, ActionVal::stair(*exp_rate, self.too_deep()))
         } else {
             (Coord::default(), ActionVal::default())
         };
-        let (search_cd, search_val) = self.dangeon.search(&dist, cur_cd).unwrap_or_default();
+        let (search_cd, search_val, search_turns) =
+            self.dangeon.search(&dist, cur_cd).unwrap_or_default();
         let hung = self.player_stat.hungry_level;
         let eat_val = self.item_list
             .any_food()
             .map_or(ActionVal::default(), |_| ActionVal::from_hung(hung));
+        // 鎧を脱いでいる間に襲われないよう、敵がいるなら着替えない(裸なら着る)
+        let (armor_act, armor_val) = match self.change_armor() {
+            Some((act, gain)) if self.enemy_list.is_empty() || self.equipment.armor_id.is_none() => {
+                (act, ActionVal::from_armor(gain))
+            }
+            _ => (Action::None, ActionVal::default()),
+        };
+        let (weapon_act, weapon_val) = match self.change_weapon() {
+            Some((act, saved)) => (act, ActionVal::from_weapon(&self.player_stat, saved)),
+            None => (Action::None, ActionVal::default()),
+        };
         let max_act = comp_action!(
             fight_val,
             recover_val,
@@ -623,7 +1182,9 @@ impl FeudalAgent {
             explore_val,
             stair_val,
             search_val,
-            eat_val
+            eat_val,
+            armor_val,
+            weapon_val
         );
         trace!(
             LOGGER,
@@ -656,13 +1217,14 @@ impl FeudalAgent {
                 )
             }
             2 => {
-                let dir = self.move_to_dest_sub(item_cd).unwrap_or_default();
-                Some(self.play_info.update(
-                    Tactics::PickItem,
-                    Action::Move(dir),
-                    Some(item_cd),
-                    item_val,
-                ))
+                let act = match self.probe_mimic(item_cd) {
+                    Some(act) => act,
+                    None => Action::Move(self.move_to_dest_sub(item_cd).unwrap_or_default()),
+                };
+                Some(
+                    self.play_info
+                        .update(Tactics::PickItem, act, Some(item_cd), item_val),
+                )
             }
             3 => {
                 let dir = self.move_to_dest_sub(explore_cd).unwrap_or_default();
@@ -687,15 +1249,16 @@ impl FeudalAgent {
             }
             5 => {
                 let dir = self.move_to_dest_sub(search_cd).unwrap_or_default();
-                let act = if dir == Direc::Stay {
-                    Action::Search
+                let (act, left) = if dir == Direc::Stay {
+                    (Action::Search, search_turns.saturating_sub(1))
                 } else {
-                    Action::Move(dir)
+                    (Action::Move(dir), search_turns)
                 };
-                Some(
+                let mut res =
                     self.play_info
-                        .update(Tactics::Search, act, Some(search_cd), search_val),
-                )
+                        .update(Tactics::Search, act, Some(search_cd), search_val);
+                res.search_left = left;
+                Some(res)
             }
             6 => Some(self.play_info.update(
                 Tactics::None,
@@ -703,6 +1266,8 @@ impl FeudalAgent {
                 None,
                 eat_val,
             )),
+            7 => Some(self.play_info.update(Tactics::None, armor_act, None, armor_val)),
+            8 => Some(self.play_info.update(Tactics::None, weapon_act, None, weapon_val)),
             _ => None,
         };
         ret
@@ -714,6 +1279,16 @@ impl FeudalAgent {
             self.play_info.init_tact();
         }
         if let Some(cd) = self.msg_flags.new_cd {
+            let prev_cd = self.play_info.cd;
+            let started = self.play_info.act != Action::None;
+            if started && !self.msg_flags.new_stage && prev_cd.dist_cheb(&cd) > 1 {
+                self.jumped(Jump::Teleport(prev_cd, cd));
+            } else if prev_cd != cd {
+                self.enemy_list.player_moved(&self.dangeon, prev_cd, cd);
+                if let Some(unseen) = self.unseen.as_mut() {
+                    unseen.not_there(cd);
+                }
+            }
             self.set_cur_cd(cd);
         } else if let Action::Move(_) = self.play_info.act {
             self.play_info.init_tact();
@@ -757,17 +1332,21 @@ impl FeudalAgent {
                 let inter = self.interupput();
                 if inter.is_some() {
                     inter
+                } else if !self.is_dest() {
+                    self.move_to_dest()
+                } else if self.play_info.search_left > 0 {
+                    let mut res = self.play_info.update_act(Action::Search);
+                    res.search_left -= 1;
+                    Some(res)
                 } else {
-                    if self.is_dest() {
-                        Some(self.play_info.update_act_init(Action::Search))
-                    } else {
-                        self.move_to_dest()
-                    }
+                    rethinked = true;
+                    self.rethink()
                 }
             }
             // HPの確認 割込み処理
+            // お腹が減ったら休み続けるか考え直す
             Tactics::Recover => {
-                if self.player_stat.have_enough_hp() {
+                if self.player_stat.have_enough_hp() || self.player_stat.hungry_level > 0 {
                     rethinked = true;
                     self.rethink()
                 } else {
@@ -784,6 +1363,8 @@ impl FeudalAgent {
                 // 座標の確認 割込み処理
                 if self.is_dest() {
                     self.play_info.init_tact();
+                    self.play_info.act = Action::DownStair;
+                    self.game_turn += 1;
                     return Some(Action::DownStair.into());
                 }
                 let inter = self.interupput();
@@ -798,6 +1379,10 @@ impl FeudalAgent {
                 self.rethink()
             }
         };
+        // 外れた武器は後で拾えるように覚えておく
+        if let Some((cd, weapon)) = self.missile.take() {
+            self.dangeon.drop_item(cd, Item::Weapon(weapon), self.game_turn);
+        }
         if nxt_playinfo.is_none() && !rethinked {
             nxt_playinfo = self.rethink();
         }
@@ -805,9 +1390,17 @@ impl FeudalAgent {
         self.msg_flags.reset();
         let cur_cd = self.play_info.cd;
         self.play_info = nxt_playinfo?;
+        if self.play_info.act.takes_turn() {
+            self.game_turn += 1;
+        }
         match self.play_info.act {
             Action::Move(d) => self.dangeon.moved(cur_cd, d),
-            Action::Throw((_, id)) => self.item_list.consume(id),
+            Action::Throw((d, id)) => {
+                self.item_list.consume(id);
+                let weapon = self.item_list.get_weapon(id).unwrap_or_default();
+                self.missile = self.missile_land(d).map(|cd| (cd, weapon));
+            }
+            Action::Search => self.dangeon.searched(cur_cd),
             _ => {}
         };
         Some(self.play_info.act.into())
@@ -817,12 +1410,46 @@ impl FeudalAgent {
         self.play_info.cd = cd;
         self.dangeon.visit(cd);
     }
+    // 行動と関係なく位置が変わった場合は、今の作戦も敵の追跡も当てにならない
+    fn jumped(&mut self, jump: Jump) {
+        info!(LOGGER, "position jump: {:?}", jump);
+        self.play_info.init_tact();
+        self.route.init();
+        self.enemy_list.init();
+        self.unseen = None;
+        self.missile = None;
+    }
+    // 見えない敵が攻撃してこられる、自分の周りのマス
+    fn unseen_around(&self, cd: Coord) -> Vec<Coord> {
+        Direc::vars()
+            .take(8)
+            .filter(|&&d| self.dangeon.can_move_enemy(cd, d))
+            .map(|d| cd + d.to_cd())
+            .filter(|&ncd| self.enemy_list.get(ncd).is_none())
+            .collect()
+    }
+    // 今の攻撃の対象が見えない敵ならそのマス
+    fn unseen_target(&self) -> Option<Coord> {
+        self.unseen.as_ref()?;
+        match self.play_info.act {
+            Action::Move(d) | Action::Fight(d) => {
+                let cd = self.play_info.cd + d.to_cd();
+                if self.enemy_list.get(cd).is_none() {
+                    Some(cd)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Reactor for FeudalAgent {
     fn action(&mut self, action_res: ActionResult, turn: usize) -> Option<Vec<u8>> {
         trace!(LOGGER, "{:?} {}", action_res, turn);
         trace!(LOGGER, "{:?}", self);
+        self.turn = turn;
         if self.dead {
             return Some(Action::Enter.into());
         }
@@ -832,6 +1459,7 @@ impl Reactor for FeudalAgent {
                 // Mergeはこのブロック内で全部終わらせる
                 // !!! 伝播が必要な情報はmsg_flagsに記録する !!!
                 let mut ret_early = None;
+                let mut detected = None;
                 let msg = {
                     let msg_str = str::from_utf8(&map[0]).unwrap();
                     let (msg, has_more) = self.msg_parser.parse(msg_str);
@@ -847,6 +1475,7 @@ impl Reactor for FeudalAgent {
                         self.item_list.merge(item_pack);
                     },
                     GameMsg::Defeated(enemy_name) => {
+                        let enemy_name = self.msg_enemy(enemy_name);
                         let removed = match self.play_info.act {
                             Action::Move(d) | Action::Fight(d) => {
                                 let base = cur_cd + d.to_cd();
@@ -868,30 +1497,40 @@ impl Reactor for FeudalAgent {
                             }
                             _ => false,
                         };
-                        if !removed {
-                            warn!(LOGGER, "defeated but not removed enemy: {:?}", enemy_name);
+                        if !removed && self.unseen_target().is_some() {
+                            self.unseen = None;
+                        } else if !removed {
+                            match self.mimic_cd() {
+                                // 正体を見る前に倒したXerocはアイテムとして覚えていた
+                                Some(cd) if enemy_name == Some(Enemy::Xeroc) => {
+                                    self.dangeon.forget_item(cd)
+                                }
+                                _ => warn!(LOGGER, "defeated but not removed enemy: {:?}", enemy_name),
+                            }
                         }
                     }
                     GameMsg::Scored(_) => match self.play_info.act {
                         Action::Move(d) | Action::Fight(d) => {
-                            let dam = {
-                                if let Some(w) = self.cur_weapon() {
-                                    w.wield().expect_val()
-                                } else {
-                                    DamageVal::default()
-                                }
-                            };
+                            let profile = self.combat_profile();
+                            let dam = profile.dam_pmf(profile.wield);
                             if let Some(hist_mut) = self.enemy_list.get_mut(cur_cd + d.to_cd()) {
-                                hist_mut.hp_ex -= dam;
+                                hist_mut.hit(&dam);
+                            } else if let Some(cd) = self.unseen_target() {
+                                if let Some(unseen) = self.unseen.as_mut() {
+                                    unseen.found(cd);
+                                    unseen.hist.hit(&dam);
+                                }
                             }
                         }
                         Action::Throw((d, id)) => {
-                            if let Some(w) = self.item_list.get_weapon(id) {
-                                let dam = w.throw().expect_val();
+                            // 当たった武器はなくなる
+                            self.missile = None;
+                            if let Some(atk) = self.item_list.get_attack(id, true) {
+                                let dam = self.combat_profile().dam_pmf(atk);
                                 if let Some(mut diter) = cur_cd.direc_iter(d) {
                                     diter.any(|cd| {
                                         if let Some(hist_mut) = self.enemy_list.get_mut(cd) {
-                                            hist_mut.hp_ex -= dam;
+                                            hist_mut.hit(&dam);
                                             true
                                         } else {
                                             false
@@ -902,36 +1541,138 @@ impl Reactor for FeudalAgent {
                         }
                         _ => {}
                     },
-                    GameMsg::Injured(enem) => {
-                        if let Some(enem_hist) = self.enemy_list.get_around_mut(cur_cd, enem) {
-                            enem_hist.running = true;
+                    // 見えない敵に攻撃が外れても、そこにいることは分かる
+                    GameMsg::Missed(_) => if let Some(cd) = self.unseen_target() {
+                        if let Some(unseen) = self.unseen.as_mut() {
+                            unseen.found(cd);
+                        }
+                    },
+                    // 攻撃してきた敵は起きている
+                    GameMsg::Injured(enem) | GameMsg::NotInjured(enem) => {
+                        let typ = self.msg_enemy(enem);
+                        if let Some(enem_hist) = self.enemy_list.get_around_mut(cur_cd, typ) {
+                            enem_hist.wake();
+                        } else {
+                            // 周りに見えている敵がいないなら見えない敵に攻撃された
+                            let around = self.unseen_around(cur_cd);
+                            let turn = self.game_turn;
+                            match self.unseen.as_mut() {
+                                Some(unseen) => unseen.attacked(&around, turn),
+                                None => {
+                                    let typ = typ.unwrap_or_else(|| self.guess_enemy());
+                                    self.unseen = Some(Unseen::new(typ, &around, turn));
+                                }
+                            }
                         }
                     }
                     GameMsg::CallIt => ret_early = Some(self.item_call.next().unwrap()),
+                    GameMsg::Detected(kind) => detected = Some(kind),
+                    GameMsg::ArmorW => if let Action::WearArmor(id) = self.play_info.act {
+                        self.equipment.armor_id = Some(id);
+                    },
+                    GameMsg::ArmorT => self.equipment.armor_id = None,
+                    GameMsg::Wielded => if let Action::WieldWeapon(id) = self.play_info.act {
+                        self.equipment.weapon_id = Some(id);
+                    },
+                    GameMsg::Cursed => self.found_cursed(),
+                    GameMsg::WhichObj => ret_early = Some(self.item_rejected()),
+                    GameMsg::Ate => {
+                        let left = self.food_left();
+                        self.food = (cmp::min(left + FOOD_RATION, STOMACH_SIZE), self.game_turn);
+                    }
+                    GameMsg::Mimic => if let Some(cd) = self.mimic_cd() {
+                        self.dangeon.forget_item(cd);
+                        self.enemy_list.add(Enemy::Xeroc, cd, self.game_turn);
+                        if let Some(enem_hist) = self.enemy_list.get_mut(cd) {
+                            enem_hist.wake();
+                        }
+                    },
+                    GameMsg::Hallucinate(hallu) => {
+                        self.hallucinating = hallu;
+                        self.dangeon.set_hallucination(hallu);
+                    }
                     _ => {}
                 }
+                let geo = geometry();
                 let stat_diff = {
-                    let stat_str = str::from_utf8(&map[LINES + 1]).unwrap();
+                    let stat_str = str::from_utf8(&map[geo.lines - 1]).unwrap();
                     if let Some(stat) = self.stat_parser.parse(stat_str) {
+                        self.msg_parser.set_level(i32::from(stat.stage_level));
                         self.player_stat.merge(stat)
                     } else {
                         PlayerStatus::default()
                     }
                 };
+                if stat_diff.arm != 0 {
+                    self.learn_armor();
+                }
+                if stat_diff.exp_level > 0 {
+                    let stat = &self.player_stat;
+                    info!(LOGGER, "level up: {} max_hp: {} next: {:?}", stat.exp_level, stat.max_hp, stat.exp_to_next());
+                }
                 // 必ずmergeする前に呼ぶ
-                if stat_diff.stage_level > 0 {
+                if stat_diff.stage_level != 0 {
+                    if self.play_info.act != Action::DownStair {
+                        self.jumped(Jump::Trapdoor(self.player_stat.stage_level));
+                    }
+                    self.msg_flags.new_stage = true;
                     self.next_stage();
                 }
-                let dangeon_msg = self.dangeon.merge(&map[1..(LINES + 1)]);
+                let map_lines = &map[1..(geo.lines - 1)];
+                let dangeon_msg = match detected {
+                    Some(kind) => {
+                        // 分かったことが増えたので計画を立て直す
+                        self.play_info.init_tact();
+                        self.route.init();
+                        if kind == Detection::Monster {
+                            let guess = if self.hallucinating {
+                                Some(self.guess_enemy())
+                            } else {
+                                None
+                            };
+                            self.enemy_list.merge_detected(map_lines, guess, self.game_turn);
+                            return Some(Action::Space.into());
+                        }
+                        if kind != Detection::Map {
+                            // 探知の結果のウィンドウにはプレイヤーも敵も写らない
+                            self.dangeon.merge_detected(map_lines, self.game_turn, kind);
+                            return Some(Action::Space.into());
+                        }
+                        self.dangeon.merge_detected(map_lines, self.game_turn, kind)
+                    }
+                    None => self.dangeon.merge(map_lines, self.game_turn),
+                };
                 if dangeon_msg == DangeonMsg::Die {
                     self.dead = true;
                     debug!(LOGGER, "Die turn: {}", turn);
                     return Some(Action::Die.into());
                 }
+                // 何か見つかったらsearchを打ち切る
+                if dangeon_msg == DangeonMsg::FindNew && self.play_info.tact == Tactics::Search {
+                    self.play_info.init_tact();
+                }
                 if let Some(cd) = self.dangeon.player_cd() {
                     self.msg_flags.set_cd(cd);
+                    if self.msg_flags.picked {
+                        self.dangeon.forget_item(cd);
+                    }
+                }
+                if self.msg_flags.sober {
+                    // 幻覚中に追跡していた敵を位置で引き継ぐ
+                    let guess = Some(self.guess_enemy());
+                    self.enemy_list.merge(&self.dangeon, guess, self.game_turn);
+                    self.enemy_list.relabel(&self.dangeon);
+                } else {
+                    let guess = if self.hallucinating {
+                        Some(self.guess_enemy())
+                    } else {
+                        None
+                    };
+                    self.enemy_list.merge(&self.dangeon, guess, self.game_turn);
+                }
+                if self.unseen.as_ref().is_some_and(|u| u.is_lost(self.game_turn)) {
+                    self.unseen = None;
                 }
-                self.enemy_list.merge(&self.dangeon);
                 trace!(LOGGER, "Enemy List {:?}", self.enemy_list);
                 if ret_early != None {
                     debug!(LOGGER, "ret_early: {:?}", ret_early);
@@ -949,41 +1690,47 @@ impl Reactor for FeudalAgent {
 mod enemy_search {
     use super::*;
     pub const SEARCH_DEPTH_MAX: usize = 10;
+    // 起きている確率がこれ以上なら動いてくるものとして読む
+    const WAKE_THRESHOLD: f64 = 0.5;
     const SEARCH_WIDTH_MAX: usize = 400;
+    // 死ぬ確率がこれ以上になったらそれ以上読まない
+    const DEATH_END: f64 = 0.95;
     // 探索用のPlayerState
     #[derive(Clone, Debug)]
     struct SearchPlayer {
         cd: Coord,
         hp_ex: DamageVal,
-        wield: Weapon,
-        throw: Vec<(Weapon, u32)>,
+        hp: HpDist,
+        profile: CombatProfile,
+        throw: Vec<(Attack, u32)>,
+        arms: Vec<Attack>,
     }
     impl SearchPlayer {
         fn initial(agent: &FeudalAgent) -> SearchPlayer {
             SearchPlayer {
                 cd: agent.play_info.cd,
                 hp_ex: DamageVal(f64::from(agent.player_stat.cur_hp)),
-                wield: agent.cur_weapon().unwrap_or_default(),
+                hp: HpDist::new(agent.player_stat.cur_hp),
+                profile: agent.combat_profile(),
                 throw: agent.throw_weapon(),
+                arms: agent.wield_cands(),
             }
         }
-        fn is_live(&self) -> bool {
-            let threshold = 0.5;
-            *self.hp_ex > threshold
-        }
     }
     // simulationするアクション
     #[derive(Copy, Clone, Debug)]
     enum TryAction {
         Move(Direc),
-        Throw((Direc, Weapon)),
+        Throw((Direc, Attack)),
+        Wield(Attack),
         Stair,
     }
     impl TryAction {
         fn to_action(&self, agent: &FeudalAgent) -> Option<Action> {
             let res = match *self {
                 TryAction::Move(d) => Action::Move(d),
-                TryAction::Throw((d, w)) => Action::Throw((d, agent.get_weapon_id(w)?)),
+                TryAction::Throw((d, atk)) => Action::Throw((d, agent.get_weapon_id(atk.weapon)?)),
+                TryAction::Wield(atk) => Action::WieldWeapon(agent.get_weapon_id(atk.weapon)?),
                 TryAction::Stair => Action::DownStair,
             };
             Some(res)
@@ -1002,21 +1749,24 @@ mod enemy_search {
         state: &SearchState,
         action: TryAction,
         turn: usize,
+        danger: &SimpleMap<f64>,
+        golds: &[Coord],
     ) -> Option<SearchState> {
         let cur_cd = state.player.cd;
         let mut next_state = state.clone();
         let mut caused_dam = ActionVal::default();
-        let (mut gained_gold, mut gained_exp) = (0, 0);
+        let mut danger_val = ActionVal::default();
+        let mut kill_val = ActionVal::default();
         {
             let cur_hp = next_state.player.hp_ex;
-            let mut cause_damage = |enem: &mut EnemyHist, dam: DamageVal| {
+            // 期待値ではなく、この一撃で倒せる確率で経験値と金を見積もる
+            let mut cause_damage = |enem: &mut EnemyHist, swing: DamagePmf| {
+                let kill = enem.hp.kill_prob(&swing, 1) - enem.hp.death_prob();
+                kill_val += ActionVal::from_kill(&agent.player_stat, enem.typ, kill);
+                enem.hit(&swing);
+                let dam = swing.expect_val();
                 caused_dam += ActionVal::from_my_dam(cur_hp, dam);
-                enem.hp_ex -= dam;
-                enem.running = true;
-                if !enem.is_live() {
-                    gained_gold = enem.typ.treasure();
-                    gained_exp = enem.typ.exp();
-                }
+                enem.wake();
             };
             // 自分の行動
             match action {
@@ -1026,15 +1776,15 @@ mod enemy_search {
                     }
                     let ncd = cur_cd + d.to_cd();
                     if let Some(enem_ref) = next_state.enemy_list.get_mut(ncd) {
-                        let prob = hit_rate_attack(&agent.player_stat, enem_ref);
-                        let dam = expect_dam_attack(&agent.player_stat, state.player.wield, false);
-                        let dam = dam * DamageVal(*prob);
-                        cause_damage(enem_ref, dam);
+                        let profile = &state.player.profile;
+                        let prob = profile.hit_rate(profile.wield, enem_ref);
+                        cause_damage(enem_ref, profile.dam_pmf(profile.wield).with_hit(prob));
                     } else {
                         next_state.player.cd = ncd;
+                        danger_val = ActionVal::danger(*danger.get(ncd).unwrap_or(&0.0));
                     }
                 }
-                TryAction::Throw((d, throw_weap)) => {
+                TryAction::Throw((d, atk)) => {
                     let mut ok = false;
                     for cd in cur_cd.direc_iter(d)? {
                         let cell = agent.dangeon.get(cd)?;
@@ -1043,10 +1793,9 @@ mod enemy_search {
                             _ => {}
                         }
                         if let Some(enem_ref) = next_state.enemy_list.get_mut(cd) {
-                            let prob = hit_rate_attack(&agent.player_stat, enem_ref);
-                            let dam = expect_dam_attack(&agent.player_stat, throw_weap, true);
-                            let dam = dam * DamageVal(*prob);
-                            cause_damage(enem_ref, dam);
+                            let profile = &state.player.profile;
+                            let prob = profile.hit_rate(atk, enem_ref);
+                            cause_damage(enem_ref, profile.dam_pmf(atk).with_hit(prob));
                             ok = true;
                             break;
                         }
@@ -1056,6 +1805,8 @@ mod enemy_search {
                         return None;
                     }
                 }
+                // 持ち替えに1ターン使う
+                TryAction::Wield(atk) => next_state.player.profile.wield = atk,
                 _ => {}
             }
         }
@@ -1071,48 +1822,54 @@ mod enemy_search {
         let cur_cd = next_state.player.cd;
         let mut received_dam = ActionVal::default();
         let mut enem_coord = next_state.enemy_list.coord_list();
-        'outer: for (i, enem_ref) in next_state.enemy_list.iter_mut().enumerate() {
-            macro_rules! is_cd_used {
-                ($cd:expr) => {(
-                    enem_coord.iter().take(i).any(|&cd| cd == $cd)
-                )}
-            }
-            if !enem_ref.running {
+        let profile = next_state.player.profile;
+        let player = &mut next_state.player;
+        for (i, enem_ref) in next_state.enemy_list.iter_mut().enumerate() {
+            let cd_used = enem_coord.iter().take(i).any(|&cd| cd == enem_ref.cd);
+            let mut attack_dam = |enem: &EnemyHist, rate: f64| {
+                // もう倒しているかもしれない
+                let rate = rate * (1.0 - enem.hp.death_prob());
+                let pmf = profile.dam_pmf_deffence(enem.typ).with_hit(ProbVal(rate));
+                player.hp = player.hp.damaged(&pmf);
+                player.hp_ex = player.hp.expect_val();
+                let dam = pmf.expect_val();
+                received_dam += ActionVal::from_enem_dam(enem.hp_ex, dam);
+            };
+            // 寝ているかもしれない敵はその場から動かず、起きている確率だけ殴ってくる
+            let awake = enem_ref.awake_prob();
+            if awake < WAKE_THRESHOLD {
+                if !cd_used && enem_ref.cd.dist_cheb(&cur_cd) <= 1 {
+                    attack_dam(enem_ref, awake);
+                    enem_ref.disturbed();
+                }
                 continue;
             }
-            // 殴れるかチェック
-            if !is_cd_used!(enem_ref.cd) {
-                for d in Direc::vars() {
-                    let cd = enem_ref.cd + d.to_cd();
-                    if cd == cur_cd {
-                        let prob = hit_rate_deffence(&agent.player_stat, &enem_ref.typ);
-                        let dam = expect_dam_deffence(enem_ref.typ);
-                        let dam = dam * DamageVal(*prob);
-                        next_state.player.hp_ex -= dam;
-                        received_dam += ActionVal::from_enem_dam(enem_ref.hp_ex, dam);
-                        continue 'outer;
-                    }
-                }
+            let acts = enemy_step(agent, enem_ref, cur_cd, &enem_coord[..i], golds);
+            let attack = acts.iter()
+                .filter(|&&(act, _)| act == EnemyAct::Attack)
+                .fold(0.0, |acc, &(_, p)| acc + p);
+            if attack > 0.0 && !cd_used {
+                attack_dam(enem_ref, attack);
             }
-            let (mut best_dist, mut best_cd) = (cur_cd.dist_euc(&enem_ref.cd), Coord::default());
-            for &d in Direc::vars() {
-                let cd = enem_ref.cd + d.to_cd();
-                if !agent.dangeon.can_move_enemy(enem_ref.cd, d) || is_cd_used!(cd) {
-                    continue;
-                }
-                let dist = cur_cd.dist_euc(&cd);
-                if dist < best_dist {
-                    best_dist = dist;
-                    best_cd = cd;
+            // 探索では一番ありそうな移動先だけを追う
+            if attack < 0.5 {
+                let next = acts.iter()
+                    .filter_map(|&(act, p)| match act {
+                        EnemyAct::Move(cd) => Some((cd, p)),
+                        EnemyAct::Attack => None,
+                    })
+                    .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+                if let Some((cd, _)) = next {
+                    enem_ref.cd = cd;
+                    enem_coord[i] = cd;
                 }
             }
-            enem_ref.cd = best_cd;
-            enem_coord[i] = best_cd;
         }
-        let mut val = ActionVal::from_gold(gained_gold) + ActionVal::from_exp(gained_exp)
-            + received_dam + caused_dam;
-        if !next_state.player.is_live() {
-            val += ActionVal::death();
+        let mut val = kill_val + received_dam + caused_dam + danger_val;
+        // HPの期待値ではなく死ぬ確率が増えた分を罰する
+        let death = next_state.player.hp.death_prob();
+        val += ActionVal(*ActionVal::death() * (death - state.player.hp.death_prob()));
+        if death >= DEATH_END {
             next_state.end = true;
         }
         val = val.comp_search_depth(turn);
@@ -1120,13 +1877,105 @@ mod enemy_search {
         next_state.actions.push(action);
         Some(next_state)
     }
-    pub fn select_throw(weapons: &[(Weapon, u32)]) -> Option<Weapon> {
+    // 敵の1ターンの行動
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum EnemyAct {
+        Attack,
+        Move(Coord),
+    }
+    // 移動モデルに従った、起きている敵の行動とその確率
+    pub fn enemy_step(
+        agent: &FeudalAgent,
+        enem: &EnemyHist,
+        player_cd: Coord,
+        used: &[Coord],
+        golds: &[Coord],
+    ) -> Vec<(EnemyAct, f64)> {
+        let adjacent = enem.cd.dist_cheb(&player_cd) <= 1;
+        match enem.typ.movement() {
+            Movement::Chase => vec![(chase(agent, enem, player_cd, used), 1.0)],
+            Movement::Stationary => if adjacent {
+                vec![(EnemyAct::Attack, 1.0)]
+            } else {
+                vec![(EnemyAct::Move(enem.cd), 1.0)]
+            },
+            Movement::Greedy => match find_gold(golds, enem) {
+                Some(gold) if !adjacent => {
+                    vec![(EnemyAct::Move(step_toward(agent, enem.cd, gold, used)), 1.0)]
+                }
+                _ => vec![(chase(agent, enem, player_cd, used), 1.0)],
+            },
+            Movement::Random => {
+                let mut res = vec![(chase(agent, enem, player_cd, used), 0.5)];
+                let cands: Vec<_> = Direc::vars()
+                    .take(8)
+                    .filter(|&&d| agent.dangeon.can_move_enemy(enem.cd, d))
+                    .map(|d| enem.cd + d.to_cd())
+                    .filter(|cd| *cd != player_cd && !used.contains(cd))
+                    .collect();
+                if cands.is_empty() {
+                    res.push((EnemyAct::Move(enem.cd), 0.5));
+                } else {
+                    let p = 0.5 / cands.len() as f64;
+                    res.extend(cands.into_iter().map(|cd| (EnemyAct::Move(cd), p)));
+                }
+                res
+            }
+        }
+    }
+    // 隣にいれば殴り、いなければ近づく(速い敵は離れていればもう1歩進む)
+    fn chase(agent: &FeudalAgent, enem: &EnemyHist, player_cd: Coord, used: &[Coord]) -> EnemyAct {
+        if enem.cd.dist_cheb(&player_cd) <= 1 {
+            return EnemyAct::Attack;
+        }
+        let mut cd = enem.cd;
+        for _ in 0..enem.typ.speed() {
+            if cd.dist_cheb(&player_cd) <= 1 {
+                break;
+            }
+            cd = step_toward(agent, cd, player_cd, used);
+        }
+        EnemyAct::Move(cd)
+    }
+    fn step_toward(agent: &FeudalAgent, from: Coord, target: Coord, used: &[Coord]) -> Coord {
+        let (mut best_dist, mut best_cd) = (target.dist_euc(&from), from);
+        for &d in Direc::vars() {
+            let cd = from + d.to_cd();
+            if !agent.dangeon.can_move_enemy(from, d) || used.contains(&cd) || cd == target {
+                continue;
+            }
+            let dist = target.dist_euc(&cd);
+            if dist < best_dist {
+                best_dist = dist;
+                best_cd = cd;
+            }
+        }
+        best_cd
+    }
+    // 覚えている金貨の位置
+    pub fn gold_coords(agent: &FeudalAgent) -> Vec<Coord> {
+        agent
+            .dangeon
+            .iter()
+            .filter(|&(cell, _)| cell.memory().is_some_and(|m| m.item == Item::Gold))
+            .map(|(_, cd)| cd)
+            .collect()
+    }
+    // 同じ部屋にある金貨
+    fn find_gold(golds: &[Coord], enem: &EnemyHist) -> Option<Coord> {
+        golds
+            .iter()
+            .filter(|cd| cd.block() == enem.cd.block())
+            .min_by_key(|cd| cd.dist_euc(&enem.cd))
+            .cloned()
+    }
+    pub fn select_throw(profile: &CombatProfile, weapons: &[(Attack, u32)]) -> Option<Attack> {
         if weapons.is_empty() {
             return None;
         }
-        let mut aim = (Weapon::None, DamageVal::default());
+        let mut aim = (Attack::default(), DamageVal::default());
         for w in weapons {
-            let dam = w.0.throw().expect_val();
+            let dam = profile.expect_dam(w.0);
             if w.1 > 0 && dam > aim.1 {
                 aim = (w.0, dam);
             }
@@ -1134,11 +1983,18 @@ mod enemy_search {
         Some(aim.0)
     }
     pub fn exec(agent: &FeudalAgent) -> Option<(ActionVal, Action)> {
-        if agent.enemy_list.is_empty() {
+        let mut enemy_list = agent.enemy_list.for_search();
+        // 見えない敵は一番いそうなマスにいるものとして読む(そこへ動けば攻撃になる)
+        if let Some(hist) = agent.unseen.as_ref().and_then(|u| u.likely()) {
+            enemy_list.0.push(hist);
+        }
+        if enemy_list.is_empty() {
             return None;
         }
+        let danger = agent.enemy_list.danger_map(&agent.dangeon, agent.game_turn);
+        let golds = gold_coords(agent);
         let init_state = SearchState {
-            enemy_list: agent.enemy_list.clone(),
+            enemy_list,
             player: SearchPlayer::initial(agent),
             actions: Vec::with_capacity(SEARCH_DEPTH_MAX),
             end: false,
@@ -1171,17 +2027,16 @@ mod enemy_search {
                 }
                 // just try to move or throw
                 for &d in Direc::vars().take(8) {
-                    if let Some(ns) = simulate_act(agent, &cur_state, TryAction::Move(d), turn) {
+                    if let Some(ns) = simulate_act(agent, &cur_state, TryAction::Move(d), turn, &danger, &golds) {
                         add_state!(ns);
                     }
                 }
-                if let Some(w) = select_throw(&cur_state.player.throw) {
-                    if w == Weapon::None {
-                        break;
-                    }
+                let throw = select_throw(&cur_state.player.profile, &cur_state.player.throw);
+                // 投げられるものがなくても、持ち替えや階段は試す
+                if let Some(w) = throw.filter(|w| w.weapon != Weapon::None) {
                     for &d in Direc::vars().take(8) {
                         if let Some(mut ns) =
-                            simulate_act(agent, &cur_state, TryAction::Throw((d, w)), turn)
+                            simulate_act(agent, &cur_state, TryAction::Throw((d, w)), turn, &danger, &golds)
                         {
                             for wep in &mut ns.player.throw {
                                 if wep.0 == w {
@@ -1192,6 +2047,15 @@ mod enemy_search {
                         }
                     }
                 }
+                // 離れた敵には弓に持ち替えて矢を射る、近づかれたら持ち直す
+                for &atk in &cur_state.player.arms {
+                    if atk.weapon == cur_state.player.profile.wield.weapon {
+                        continue;
+                    }
+                    if let Some(ns) = simulate_act(agent, &cur_state, TryAction::Wield(atk), turn, &danger, &golds) {
+                        add_state!(ns);
+                    }
+                }
                 if let Some(cell) = agent.dangeon.get(cur_state.player.cd) {
                     if cell.surface() == Surface::Stair {
                         cur_state.actions.push(TryAction::Stair);
@@ -1202,6 +2066,28 @@ mod enemy_search {
             }
             state_list = next_states;
         }
+        // 読み切れなかった隣の敵との殴り合いは、勝つ確率と死ぬ確率で評価する
+        let mut fight_cache = HashMap::new();
+        for st in state_list.iter_mut().filter(|st| !st.end) {
+            let hp = st.player.hp.to_bits();
+            for enem in st.enemy_list.iter() {
+                if enem.cd.dist_cheb(&st.player.cd) > 1 || enem.awake_prob() < WAKE_THRESHOLD {
+                    continue;
+                }
+                // 持ち替えた武器やHPの分布、起きている確率が違えば結果も違う
+                let key = (
+                    enem.typ,
+                    hp.clone(),
+                    st.player.profile.wield,
+                    enem.hp_ex.to_bits(),
+                    enem.awake_prob().to_bits(),
+                );
+                let res = *fight_cache.entry(key).or_insert_with(|| {
+                    eval_fight_enemy(&st.player.profile, &st.player.hp, enem, SEARCH_DEPTH_MAX)
+                });
+                st.val += ActionVal::from_fight(&agent.player_stat, enem.typ, res);
+            }
+        }
         let best_state = state_list.iter().max()?;
         trace!(
             LOGGER,
@@ -1240,6 +2126,13 @@ mod test {
     use super::*;
     use testutils::*;
     #[test]
+    fn test_search_val() {
+        // 未発見の区画が多い方向ほどsearchする価値が高い
+        let vals: Vec<_> = (0..4).map(ActionVal::search).collect();
+        assert!(vals.windows(2).all(|w| w[0] <= w[1]));
+        assert!(vals[0] < vals[1] && vals[1] < vals[2]);
+    }
+    #[test]
     fn test_comp_action() {
         let a = ActionVal(5.0);
         let b = ActionVal(6.0);
@@ -1290,9 +2183,328 @@ mod test {
         ];
         let mut dangeon = make_dangeon(MAP1);
         for (map, &ans) in maps.iter().zip(answers.iter()) {
-            dangeon.merge(&str_to_buf(map));
-            enemy_list.merge(&dangeon);
+            dangeon.merge(&str_to_buf(map), 0);
+            enemy_list.merge(&dangeon, None, 0);
             assert_eq!(ans, enemy_list.0[0].cd);
         }
     }
+    #[test]
+    fn test_enemy_list_hallucination() {
+        let mut enemy_list = EnemyList::new();
+        let mut dangeon = make_dangeon(MAP1);
+        enemy_list.merge(&dangeon, None, 0);
+        dangeon.set_hallucination(true);
+        // 幻覚中は文字が変わっても同じ敵として追跡する
+        for (map, fake) in [(MAP2, "X"), (MAP3, "Q")].iter() {
+            dangeon.merge(&str_to_buf(&map.replace("B", fake)), 0);
+            enemy_list.merge(&dangeon, Some(Enemy::Bat), 0);
+            assert_eq!(enemy_list.0.len(), 1);
+            assert_eq!(enemy_list.0[0].typ, Enemy::Bat);
+        }
+        assert_eq!(enemy_list.0[0].cd, Coord { x: 39, y: 4 });
+        dangeon.set_hallucination(false);
+        dangeon.merge(&str_to_buf(&MAP4.replace("B", "K")), 0);
+        enemy_list.merge(&dangeon, Some(Enemy::Bat), 0);
+        enemy_list.relabel(&dangeon);
+        assert_eq!(enemy_list.0.len(), 1);
+        assert_eq!(enemy_list.0[0].typ, Enemy::Kestrel);
+    }
+    const MAP_MIMIC: &str = "
+ ----------------
+ |..............|
+ |..!.......@...|
+ |..............|
+ ----------------
+";
+    #[test]
+    fn test_mimic() {
+        let mut agent = FeudalAgent::new();
+        agent.dangeon = make_dangeon(MAP_MIMIC);
+        agent.play_info.cd = agent.dangeon.player_cd().unwrap();
+        let item_cd = Coord { x: 4, y: 2 };
+        // 浅い階ではXerocは出ない
+        assert_eq!(agent.mimic_risk(item_cd), 0.0);
+        assert_eq!(agent.probe_mimic(item_cd), None);
+        agent.player_stat.stage_level = 20;
+        assert!(agent.mimic_risk(item_cd) > 0.0);
+        let probe = Action::Throw((Direc::Left, b'e'));
+        assert_eq!(agent.probe_mimic(item_cd), Some(probe));
+        // 一度確かめたら安全
+        assert_eq!(agent.mimic_risk(item_cd), 0.0);
+        assert_eq!(agent.probe_mimic(item_cd), None);
+        agent.play_info.act = probe;
+        assert_eq!(agent.mimic_cd(), Some(item_cd));
+    }
+    #[test]
+    fn test_missile() {
+        let mut agent = FeudalAgent::new();
+        agent.dangeon = make_dangeon(MAP_MIMIC);
+        let player = agent.dangeon.player_cd().unwrap();
+        agent.play_info.cd = player;
+        // 敵がいなければ壁の手前に落ちる
+        assert_eq!(agent.missile_land(Direc::Right), Some(Coord { x: 15, y: 2 }));
+        let enem_cd = Coord { x: 13, y: 2 };
+        agent.enemy_list.add(Enemy::Hobgoblin, enem_cd, 0);
+        assert_eq!(agent.missile_land(Direc::Right), Some(enem_cd));
+        // 矢があるので弓と、近接用のmaceに持ち替えられる
+        let arms: Vec<_> = agent.wield_cands().iter().map(|atk| atk.weapon).collect();
+        assert_eq!(arms, vec![Weapon::Bow, Weapon::Mace]);
+        agent.item_list.get_mut(b'e').unwrap().num = 0;
+        let arms: Vec<_> = agent.wield_cands().iter().map(|atk| atk.weapon).collect();
+        assert_eq!(arms, vec![Weapon::Mace]);
+    }
+    #[test]
+    fn test_recover() {
+        let mut agent = FeudalAgent::new();
+        let stat = &mut agent.player_stat;
+        stat.cur_hp = 4;
+        // レベル1では19ターンに1回復、指輪があれば毎ターン
+        assert_eq!(stat.turns_to_hp(8, 0), 76);
+        assert_eq!(stat.turns_to_hp(8, 1), 4);
+        stat.exp_level = 10;
+        assert_eq!(stat.turns_to_hp(8, 0), 6);
+        assert_eq!(stat.turns_to_hp(100, 0), 12);
+        // 表示されている空腹の段階と矛盾しない見積もり
+        assert_eq!(stat.food_left(FOOD_RATION, 5000, 0.0), 300);
+        stat.hungry_level = 1;
+        assert_eq!(stat.food_left(FOOD_RATION, 0, 0.0), 299);
+        // 回復の指輪は1ターンに2、increase damageは3ターンに1余計に減らす
+        stat.hungry_level = 0;
+        let eat = ring_eat("regeneration") + ring_eat("increase damage");
+        assert_eq!(stat.food_left(FOOD_RATION, 250, eat), 467);
+        assert_eq!(stat.food_left(FOOD_RATION, 300, ring_eat("slow digestion")), 1150);
+        stat.exp_level = 1;
+        stat.hungry_level = 2;
+        // --More--を送っても時間は進まない
+        assert!(!Action::Space.takes_turn() && Action::Search.takes_turn());
+        agent.game_turn = 1250;
+        // 食べ物がないのに飢えるまで休むのは割に合わない
+        let rest = agent.recover_val();
+        assert!(rest > ActionVal::default());
+        agent.item_list.get_mut(b'a').unwrap().num = 0;
+        agent.item_list.get_mut(b'a').unwrap().typ = Item::None;
+        assert!(agent.recover_val() < rest);
+        agent.player_stat.cur_hp = 12;
+        assert_eq!(agent.recover_val(), ActionVal::default());
+    }
+    #[test]
+    fn test_exp() {
+        let mut stat = PlayerStatus::initial();
+        assert_eq!(stat.exp_to_next(), Some(10));
+        assert_approx_eq!(stat.level_progress(5), 0.5);
+        // 2レベル分をまたぐ
+        stat.exp = 8;
+        assert_approx_eq!(stat.level_progress(17), 0.2 + 1.0 + 0.25);
+        assert_approx_eq!(stat.max_hp_after(2.0), 23.0);
+        stat.exp_level = 21;
+        assert_eq!(stat.exp_to_next(), None);
+        assert_approx_eq!(stat.level_progress(1000), 0.0);
+        // 最大HPが多いほど同じ経験値の価値は下がる
+        let weak = PlayerStatus::initial();
+        let mut strong = PlayerStatus::initial();
+        strong.max_hp = 40;
+        assert!(ActionVal::from_exp(&weak, 3) > ActionVal::from_exp(&strong, 3));
+    }
+    #[test]
+    fn test_armor() {
+        let mut agent = FeudalAgent::new();
+        // 初期装備の+1 ring mailより補正の分からないscale mailの方が硬い
+        assert_eq!(agent.armor_arm(Some(b'b')), 4);
+        assert_eq!(agent.change_armor(), None);
+        agent.item_list.merge(ItemPack::new(b'f', "", 1, Item::Armor(Armor::Scale)));
+        assert_eq!(agent.armor_arm(Some(b'f')), 4);
+        agent.item_list.merge(ItemPack::new(b'g', "", 1, Item::Armor(Armor::Splint)));
+        assert_eq!(agent.change_armor(), Some((Action::TakeArmorOff, 2)));
+        // 脱いだら一番良いものを着る
+        agent.equipment.armor_id = None;
+        assert_eq!(agent.change_armor(), Some((Action::WearArmor(b'g'), 6)));
+        // 着てみたら-2だった
+        agent.equipment.armor_id = Some(b'g');
+        agent.player_stat.arm = 4;
+        agent.learn_armor();
+        assert_eq!(agent.armor_arm(Some(b'g')), 4);
+        assert_eq!(agent.change_armor(), None);
+        // 呪われていて脱げなかった鎧は脱ごうとしない
+        agent.item_list.merge(ItemPack::new(b'h', "", 1, Item::Armor(Armor::Plate)));
+        assert_eq!(agent.change_armor(), Some((Action::TakeArmorOff, 3)));
+        agent.play_info.act = Action::TakeArmorOff;
+        agent.found_cursed();
+        assert_eq!(agent.change_armor(), None);
+    }
+    #[test]
+    fn test_weapon() {
+        let mut agent = FeudalAgent::new();
+        // 初期装備では+1,+1のmaceが一番良い
+        assert_eq!(agent.change_weapon(), None);
+        agent.item_list.merge(ItemPack::new(b'f', "", 1, Item::Weapon(Weapon::Dagger)));
+        assert_eq!(agent.change_weapon(), None);
+        // 補正の分からないtwo handed swordでも4d4ならmaceより強い(両手持ちの罰はない)
+        let sword = ItemPack::new(b'g', "", 1, Item::Weapon(Weapon::TwoHandedSword));
+        agent.item_list.merge(sword.clone());
+        let (act, saved) = agent.change_weapon().unwrap();
+        assert_eq!(act, Action::WieldWeapon(b'g'));
+        assert!(saved > 0.0);
+        // 呪われているかもしれないぶん、+0と分かっているものより危ない
+        let unknown = agent.wield_danger(Some(b'g'));
+        agent.item_list.get_mut(b'g').unwrap().plus = Some((0, 0));
+        assert!(agent.wield_danger(Some(b'g')) < unknown);
+        assert!(unknown < agent.wield_danger(None));
+        // 持ち物を聞き直されたら、そのアイテムは持っていなかった
+        agent.play_info.act = Action::WieldWeapon(b'g');
+        let escape: Vec<u8> = Action::Escape.into();
+        assert_eq!(agent.item_rejected(), escape);
+        assert!(agent.msg_flags.invalid_item);
+        assert_eq!(agent.change_weapon(), None);
+        // 呪われていて持ち替えられなかったら諦める
+        agent.cursed.push(b'c');
+        assert_eq!(agent.change_weapon(), None);
+        assert!(agent.wield_cands().is_empty());
+    }
+    #[test]
+    fn test_detect_monster() {
+        let mut agent = FeudalAgent::new();
+        agent.dangeon = make_dangeon(MAP_MIMIC);
+        let mut window = vec![vec![b' '; geometry().columns]; geometry().map_lines()];
+        window[3][30] = b'T';
+        agent.enemy_list.merge_detected(&window, None, 5);
+        let troll = Coord::new(30, 3);
+        let hist = agent.enemy_list.get(troll).unwrap();
+        assert_eq!(hist.typ, Enemy::Troll);
+        assert!(hist.detected && !hist.visible);
+        // 画面から消えても、起こしてしまっても忘れない
+        agent.enemy_list.merge(&agent.dangeon, None, 6);
+        agent.enemy_list.get_mut(troll).unwrap().wake();
+        agent.enemy_list.drop_lost(6 + TRACK_TURN_MAX * 2);
+        assert!(agent.enemy_list.get(troll).is_some());
+    }
+    #[test]
+    fn test_unseen() {
+        let mut agent = FeudalAgent::new();
+        agent.dangeon = make_dangeon(MAP_MIMIC);
+        let player = agent.dangeon.player_cd().unwrap();
+        agent.play_info.cd = player;
+        let around = agent.unseen_around(player);
+        assert_eq!(around.len(), 8);
+        let mut unseen = Unseen::new(Enemy::Phantom, &around, 0);
+        // 右に動けたのでそこにはいない
+        let right = player + Direc::Right.to_cd();
+        unseen.not_there(right);
+        assert_eq!(unseen.cands.len(), 7);
+        // 動いた先で攻撃されたら、元の隣のマスから近い所ほどいそう
+        let around = agent.unseen_around(right);
+        unseen.attacked(&around, 1);
+        let far = right + Direc::Right.to_cd();
+        let near = player + Direc::Up.to_cd();
+        let prob = |cd: Coord| unseen.cands.iter().find(|c| c.0 == cd).unwrap().1;
+        assert!(prob(near) > prob(far));
+        // 攻撃が外れたマスにいる
+        agent.unseen = Some(unseen);
+        agent.play_info.cd = right;
+        agent.play_info.act = Action::Move(Direc::Up);
+        let target = agent.unseen_target().unwrap();
+        let unseen = agent.unseen.as_mut().unwrap();
+        unseen.found(target);
+        assert_eq!(unseen.likely().unwrap().cd, right + Direc::Up.to_cd());
+        assert!(!unseen.is_lost(1 + UNSEEN_TURN_MAX));
+        assert!(unseen.is_lost(2 + UNSEEN_TURN_MAX));
+    }
+    const MAP_MOVE: &str = "
+ ----------------
+ |.*...O........|
+ |..............|
+ |..F.....K....@|
+ ----------------
+";
+    #[test]
+    fn test_enemy_step() {
+        use self::enemy_search::{enemy_step, gold_coords, EnemyAct};
+        let mut agent = FeudalAgent::new();
+        agent.dangeon = make_dangeon(MAP_MOVE);
+        let player = agent.dangeon.player_cd().unwrap();
+        let golds = gold_coords(&agent);
+        let step = |typ: Enemy, x: i32, y: i32| {
+            enemy_step(&agent, &EnemyHist::new(typ, Coord { x, y }), player, &[], &golds)
+        };
+        // Venus Flytrapは動かず、隣に来たら攻撃する
+        let trap = Coord { x: 4, y: 3 };
+        assert_eq!(step(Enemy::Flytrap, 4, 3), vec![(EnemyAct::Move(trap), 1.0)]);
+        assert_eq!(step(Enemy::Flytrap, 14, 2), vec![(EnemyAct::Attack, 1.0)]);
+        // 速い敵は2歩近づく
+        let fast = Coord { x: 12, y: 3 };
+        assert_eq!(step(Enemy::Kestrel, 10, 3), vec![(EnemyAct::Move(fast), 1.0)]);
+        // Batは半分の確率ででたらめに動く
+        let bat = step(Enemy::Bat, 10, 3);
+        assert!(bat.contains(&(EnemyAct::Move(fast), 0.5)));
+        let total = bat.iter().fold(0.0, |acc, &(_, p)| acc + p);
+        assert!((total - 1.0).abs() < 1e-6);
+        // Orcは先に金貨を取りに行く
+        match step(Enemy::Orc, 7, 1)[0].0 {
+            EnemyAct::Move(cd) => assert!(cd.x < 7),
+            EnemyAct::Attack => panic!(),
+        }
+    }
+    #[test]
+    fn test_enemy_track() {
+        let mut enemy_list = EnemyList::new();
+        let mut dangeon = make_dangeon(MAP1);
+        enemy_list.merge(&dangeon, None, 0);
+        enemy_list.0[0].running = true;
+        // 見えなくなっても追跡し、いそうな場所を予測する
+        dangeon.merge(&str_to_buf(&MAP1.replace("B", ".")), 1);
+        enemy_list.merge(&dangeon, None, 1);
+        assert_eq!(enemy_list.0.len(), 1);
+        let danger = enemy_list.danger_map(&dangeon, 2);
+        let total = danger.iter().fold(0.0, |acc, (p, _)| acc + p);
+        assert!((total - 1.0).abs() < 1e-6);
+        assert!(*danger.get(Coord { x: 36, y: 4 }).unwrap() > 0.0);
+        assert_eq!(*danger.get(Coord { x: 30, y: 3 }).unwrap(), 0.0);
+        assert!(enemy_list.for_search().is_empty());
+        // 予測範囲に現れたら同じ敵とみなす
+        dangeon.merge(&str_to_buf(MAP3), 2);
+        enemy_list.merge(&dangeon, None, 2);
+        assert_eq!(enemy_list.0.len(), 1);
+        assert_eq!(enemy_list.0[0].cd, Coord { x: 39, y: 4 });
+        // 長い間見えなければ忘れる
+        dangeon.merge(&str_to_buf(&MAP3.replace("B", ".")), 3);
+        enemy_list.merge(&dangeon, None, 3);
+        enemy_list.merge(&dangeon, None, 3 + TRACK_TURN_MAX + 1);
+        assert!(enemy_list.is_empty());
+    }
+    #[test]
+    fn test_enemy_stayed() {
+        let mut enemy_list = EnemyList::new();
+        let dangeon = make_dangeon(MAP1);
+        enemy_list.merge(&dangeon, None, 0);
+        enemy_list.0[0].disturbed();
+        let awake = enemy_list.0[0].awake;
+        // 同じターンの画面では動かなかったことにならない
+        enemy_list.merge(&dangeon, None, 0);
+        assert_eq!(enemy_list.0[0].awake, awake);
+        let mut expect = enemy_list.0[0].clone();
+        expect.stayed();
+        enemy_list.merge(&dangeon, None, 1);
+        enemy_list.merge(&dangeon, None, 1);
+        assert_eq!(enemy_list.0[0].awake, expect.awake);
+        // 動かない敵が留まっていても何も分からない
+        let mut enemy_list = EnemyList::new();
+        let dangeon = make_dangeon(&MAP1.replace("B", "F"));
+        enemy_list.merge(&dangeon, None, 0);
+        enemy_list.0[0].awake = 0.5;
+        enemy_list.merge(&dangeon, None, 1);
+        assert_eq!(enemy_list.0[0].awake, 0.5);
+    }
+    #[test]
+    fn test_enemy_regenerate() {
+        let mut enemy_list = EnemyList::new();
+        let dangeon = make_dangeon(&MAP1.replace("B", "T"));
+        enemy_list.merge(&dangeon, None, 0);
+        enemy_list.0[0].hit(&DamagePmf::constant(5));
+        let hp = *enemy_list.0[0].hp_ex;
+        // --More--などで画面を何度読んでも、ゲーム内のターンが進まなければ回復しない
+        enemy_list.merge(&dangeon, None, 0);
+        enemy_list.merge(&dangeon, None, 0);
+        assert!((*enemy_list.0[0].hp_ex - hp).abs() < 1e-6);
+        enemy_list.merge(&dangeon, None, 2);
+        assert!((*enemy_list.0[0].hp_ex - hp - 2.0).abs() < 1e-6);
+    }
 }
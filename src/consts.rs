@@ -5,6 +5,7 @@ use sloggers::Build;
 use sloggers::file::FileLoggerBuilder;
 use sloggers::null::NullLoggerBuilder;
 use sloggers::types::Severity;
+use std::sync::atomic::{AtomicUsize, Ordering};
 lazy_static!{
     pub static ref MATCHES: ArgMatches<'static> =
         clap_app!(rogue_ai_2nd =>
@@ -16,6 +17,9 @@ lazy_static!{
                   (@arg MAX_LOOP: -M --maxloop +takes_value "Max Loop number")
                   (@arg INTERVAL: -I --interval +takes_value "Draw interval")
                   (@arg VIS: -V --vis "Visualize")
+                  (@arg COLUMNS: -C --columns +takes_value "Screen columns")
+                  (@arg LINES: --lines +takes_value "Screen lines")
+                  (@arg SIMULATE: -S --simulate +takes_value "Simulate fights against a monster (e.g. H)")
         )
         .get_matches();
     pub static ref LEVEL: Severity = match MATCHES.value_of("DEBUG_LEVEL") {
@@ -41,6 +45,40 @@ lazy_static!{
     }.ok().unwrap();
 }
 
-pub const COLUMNS: usize = 80;
-pub const LINES: usize = 22;
-pub const INF_DIST: i32 = (COLUMNS * LINES) as i32;
+pub const INF_DIST: i32 = i32::MAX / 2;
+
+// 画面サイズ 起動時にset_geometryで変更できる
+static SCREEN_COLUMNS: AtomicUsize = AtomicUsize::new(80);
+static SCREEN_LINES: AtomicUsize = AtomicUsize::new(24);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    pub columns: usize,
+    pub lines: usize, // メッセージ欄とステータス欄を含む画面全体の行数
+}
+
+impl Geometry {
+    pub fn new(columns: usize, lines: usize) -> Geometry {
+        Geometry { columns, lines }
+    }
+    // マップ部分の行数
+    pub fn map_lines(&self) -> usize {
+        self.lines - 2
+    }
+    // rogueの部屋スロット1つぶんの大きさ(rooms.cのbsze)
+    pub fn block_size(&self) -> (i32, i32) {
+        ((self.columns / 3) as i32, (self.lines / 3) as i32)
+    }
+}
+
+pub fn geometry() -> Geometry {
+    Geometry::new(
+        SCREEN_COLUMNS.load(Ordering::Relaxed),
+        SCREEN_LINES.load(Ordering::Relaxed),
+    )
+}
+
+pub fn set_geometry(geo: Geometry) {
+    SCREEN_COLUMNS.store(geo.columns, Ordering::Relaxed);
+    SCREEN_LINES.store(geo.lines, Ordering::Relaxed);
+}
@@ -1,5 +1,5 @@
 // calc damage
-use data::{Enemy, EnemyHist, PlayerStatus, Weapon};
+use data::{appear_prob, Enemy, EnemyHist, PlayerStatus, Weapon};
 use rand::{thread_rng, Rng};
 use std::cmp::{max, min, Ordering};
 use std::ops::Deref;
@@ -37,27 +37,100 @@ fn hit_rate_sub(level: i32, armor: i32, correct: i32) -> ProbVal {
     ProbVal(f64::from(val) / 20.0f64)
 }
 
-// 補正値はRunningとstrength以外考慮しない
-pub fn hit_rate_attack(player: &PlayerStatus, ene: &EnemyHist) -> ProbVal {
-    let st = player.cur_str;
-    let str_p = str_plus(st).unwrap_or_default() + if ene.running { 0 } else { 4 };
-    hit_rate_sub(player.exp_level, ene.typ.defence(), str_p + 1)
+// 1回の攻撃 使う武器とその(命中, ダメージ)補正
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Attack {
+    pub weapon: Weapon,
+    pub plus: (i32, i32),
+    pub throw: bool,
 }
 
-// 補正値は考慮しない
-pub fn hit_rate_deffence(player: &PlayerStatus, ene: &Enemy) -> ProbVal {
-    let arm = 10 - player.arm;
-    hit_rate_sub(ene.level(), i32::from(arm), 1)
+impl Attack {
+    pub fn new(weapon: Weapon, plus: (i32, i32), throw: bool) -> Attack {
+        Attack { weapon, plus, throw }
+    }
 }
 
-pub fn expect_dam_attack(player: &PlayerStatus, weapon: Weapon, throw: bool) -> DamageVal {
-    let dice = if throw {
-        weapon.throw()
-    } else {
-        weapon.wield()
-    };
-    let plus = DamageVal(f64::from(add_dam(player.cur_str).unwrap_or_default()));
-    dice.expect_val() + plus
+// 命中とダメージに効くプレイヤー側の補正をまとめたもの
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CombatProfile {
+    pub level: i32,
+    pub strength: i32,
+    pub arm: i32, // 10 - 表示されているArm
+    pub wield: Attack,
+    pub ring_plus: (i32, i32), // dexterityとincrease damageの指輪
+}
+
+impl CombatProfile {
+    // 初期装備(+1,+1のmace)
+    pub fn initial() -> CombatProfile {
+        let mut res = CombatProfile::new(&PlayerStatus::initial(), Weapon::Mace);
+        res.wield.plus = (1, 1);
+        res
+    }
+    // 武器の補正が分からない時はこれ
+    pub fn new(player: &PlayerStatus, weapon: Weapon) -> CombatProfile {
+        CombatProfile {
+            level: player.exp_level,
+            strength: player.cur_str,
+            arm: 10 - i32::from(player.arm),
+            wield: Attack::new(weapon, (0, 0), false),
+            ring_plus: (0, 0),
+        }
+    }
+    // rogueのroll_emと同じように、ダイスと補正の合計を決める
+    fn attack_dice(&self, atk: Attack) -> (Dice, i32, i32) {
+        let (mut hplus, mut dplus) = atk.plus;
+        let dice = if !atk.throw {
+            // 指輪は手に持った武器で殴るときだけ効く
+            if atk.weapon != Weapon::None {
+                hplus += self.ring_plus.0;
+                dplus += self.ring_plus.1;
+            }
+            atk.weapon.wield()
+        } else {
+            match atk.weapon.launcher() {
+                // 弓で射た矢には弓の補正も乗る
+                Some(l) if l == self.wield.weapon => {
+                    hplus += self.wield.plus.0;
+                    dplus += self.wield.plus.1;
+                    atk.weapon.throw()
+                }
+                // 弓なしで投げた矢はただの1d1
+                Some(_) => atk.weapon.wield(),
+                None => atk.weapon.throw(),
+            }
+        };
+        hplus += str_plus(self.strength).unwrap_or_default();
+        dplus += add_dam(self.strength).unwrap_or_default();
+        (dice, hplus, dplus)
+    }
+    // 寝ている敵には+4なので、起きている確率で重み付けする
+    pub fn hit_rate(&self, atk: Attack, ene: &EnemyHist) -> ProbVal {
+        let (_, hplus, _) = self.attack_dice(atk);
+        let awake = hit_rate_sub(self.level, ene.typ.defence(), hplus + 1);
+        let asleep = hit_rate_sub(self.level, ene.typ.defence(), hplus + 5);
+        let p = ene.awake_prob();
+        ProbVal(*awake * p + *asleep * (1.0 - p))
+    }
+    pub fn dam_pmf(&self, atk: Attack) -> DamagePmf {
+        let (dice, _, dplus) = self.attack_dice(atk);
+        dice.pmf().plus(dplus)
+    }
+    pub fn expect_dam(&self, atk: Attack) -> DamageVal {
+        self.dam_pmf(atk).expect_val()
+    }
+    // 敵の攻撃の補正値は考慮しない
+    pub fn hit_rate_deffence(&self, ene: Enemy) -> ProbVal {
+        hit_rate_sub(ene.level(), self.arm, 1)
+    }
+    // 敵の攻撃はそれぞれ命中判定がある
+    pub fn dam_pmf_deffence(&self, enem: Enemy) -> DamagePmf {
+        let hit = self.hit_rate_deffence(enem);
+        enem.attack()
+            .iter()
+            .fold(DamagePmf::constant(0), |acc, d| acc.convolve(&d.pmf().with_hit(hit)))
+    }
 }
 
 // 補正値はたぶんない
@@ -65,6 +138,26 @@ pub fn expect_dam_deffence(enem: Enemy) -> DamageVal {
     enem.attack().expect_val()
 }
 
+// 最初から階にいる敵とさまよう敵には同じくらい出会う
+const WANDER_RATE: f64 = 0.5;
+
+// その階で出会う敵1体を倒すまでに受けるダメージの期待値
+pub fn level_danger(profile: &CombatProfile, level: i32) -> DamageVal {
+    let fight = |enem: Enemy| {
+        let mut hist = EnemyHist::from_type(enem);
+        hist.wake();
+        let attack = *profile.hit_rate(profile.wield, &hist) * *profile.expect_dam(profile.wield);
+        let turns = *hist.hp_ex / attack.max(0.1);
+        turns * *profile.hit_rate_deffence(enem) * *expect_dam_deffence(enem)
+    };
+    let sum = |wander: bool| {
+        appear_prob(level, wander)
+            .into_iter()
+            .fold(0.0, |acc, (enem, p)| acc + p * fight(enem))
+    };
+    DamageVal(sum(false) * (1.0 - WANDER_RATE) + sum(true) * WANDER_RATE)
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Dice {
     num: i32,
@@ -72,7 +165,7 @@ pub struct Dice {
 }
 
 impl Dice {
-    pub fn new(n: i32, t: i32) -> Dice {
+    pub const fn new(n: i32, t: i32) -> Dice {
         Dice { num: n, typ: t }
     }
 }
@@ -82,14 +175,22 @@ pub trait DiceDamage {
     fn random_val(self) -> DamageVal;
     fn min_val(self) -> DamageVal;
     fn max_val(self) -> DamageVal;
+    fn pmf(self) -> DamagePmf;
 }
 
 impl DiceDamage for Dice {
     fn expect_val(self) -> DamageVal {
+        // Aquatorなどの攻撃は0d0
+        if self.typ == 0 {
+            return DamageVal::default();
+        }
         let sum = (1..self.typ + 1).fold(0f64, |acc, x| acc + f64::from(x));
         DamageVal(sum * f64::from(self.num) / f64::from(self.typ))
     }
     fn random_val(self) -> DamageVal {
+        if self.typ <= 0 {
+            return DamageVal::default();
+        }
         let mut rng = thread_rng();
         let sum = (0..self.num).fold(0.0, |acc, _| {
             acc + f64::from(rng.gen_range(0, self.typ)) + 1.0
@@ -102,6 +203,17 @@ impl DiceDamage for Dice {
     fn max_val(self) -> DamageVal {
         DamageVal(f64::from(self.num * self.typ))
     }
+    fn pmf(self) -> DamagePmf {
+        if self.typ <= 0 {
+            return DamagePmf::constant(0);
+        }
+        let one = DamagePmf(
+            (0..self.typ + 1)
+                .map(|i| if i == 0 { 0.0 } else { 1.0 / f64::from(self.typ) })
+                .collect(),
+        );
+        (0..self.num).fold(DamagePmf::constant(0), |acc, _| acc.convolve(&one))
+    }
 }
 
 impl<I, T> DiceDamage for I
@@ -124,7 +236,285 @@ where
     }
     fn max_val(self) -> DamageVal {
         self.into_iter()
-            .fold(DamageVal::default(), |acc, d| acc + d.random_val())
+            .fold(DamageVal::default(), |acc, d| acc + d.max_val())
+    }
+    // 独立なので畳み込む
+    fn pmf(self) -> DamagePmf {
+        self.into_iter()
+            .fold(DamagePmf::constant(0), |acc, d| acc.convolve(&d.pmf()))
+    }
+}
+
+// ダメージの確率分布 (0番目がダメージ0の確率)
+#[derive(Clone, Debug, PartialEq)]
+pub struct DamagePmf(Vec<f64>);
+
+impl DamagePmf {
+    pub fn constant(n: i32) -> DamagePmf {
+        let n = max(n, 0) as usize;
+        let mut res = vec![0.0; n + 1];
+        res[n] = 1.0;
+        DamagePmf(res)
+    }
+    pub fn convolve(&self, other: &DamagePmf) -> DamagePmf {
+        let mut res = vec![0.0; self.0.len() + other.0.len() - 1];
+        for (i, p) in self.0.iter().enumerate() {
+            for (j, q) in other.0.iter().enumerate() {
+                res[i + j] += p * q;
+            }
+        }
+        DamagePmf(res)
+    }
+    // 力による補正など ダメージは0未満にはならない
+    pub fn plus(&self, n: i32) -> DamagePmf {
+        let mut res = vec![0.0; max(self.0.len() as i32 + n, 1) as usize];
+        for (i, p) in self.0.iter().enumerate() {
+            res[max(i as i32 + n, 0) as usize] += p;
+        }
+        DamagePmf(res)
+    }
+    // 確率hitで当たる攻撃
+    pub fn with_hit(&self, hit: ProbVal) -> DamagePmf {
+        let mut res: Vec<_> = self.0.iter().map(|p| p * *hit).collect();
+        res[0] += 1.0 - *hit;
+        DamagePmf(res)
+    }
+    // n回分の合計
+    pub fn repeat(&self, n: usize) -> DamagePmf {
+        (0..n).fold(DamagePmf::constant(0), |acc, _| acc.convolve(self))
+    }
+    pub fn expect_val(&self) -> DamageVal {
+        let sum = self.0
+            .iter()
+            .enumerate()
+            .fold(0.0, |acc, (i, p)| acc + i as f64 * p);
+        DamageVal(sum)
+    }
+    // ダメージがn以上になる確率
+    pub fn at_least(&self, n: i32) -> f64 {
+        self.0.iter().skip(max(n, 0) as usize).sum()
+    }
+}
+
+// HPの確率分布 (0番目は死んでいる確率)
+#[derive(Clone, Debug, PartialEq)]
+pub struct HpDist(Vec<f64>);
+
+impl HpDist {
+    pub fn new(hp: i32) -> HpDist {
+        let hp = max(hp, 0) as usize;
+        let mut res = vec![0.0; hp + 1];
+        res[hp] = 1.0;
+        HpDist(res)
+    }
+    pub fn from_pmf(pmf: DamagePmf) -> HpDist {
+        HpDist(pmf.0)
+    }
+    // まだ生きていると分かった
+    pub fn alive(&self) -> HpDist {
+        let sum: f64 = self.0.iter().skip(1).sum();
+        // 死んでいるはずなのに生きていたら、見積もりが外れている
+        if sum <= 0.0 {
+            return HpDist::new(1);
+        }
+        let mut res: Vec<_> = self.0.iter().map(|p| p / sum).collect();
+        res[0] = 0.0;
+        HpDist(res)
+    }
+    // 生きていればn回復する(capまで)
+    pub fn healed(&self, n: usize, cap: usize) -> HpDist {
+        let mut res = vec![0.0; max(self.0.len(), cap + 1)];
+        res[0] = self.0[0];
+        for (hp, p) in self.0.iter().enumerate().skip(1) {
+            res[min(hp + n, max(cap, hp))] += p;
+        }
+        HpDist(res)
+    }
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> i32 {
+        let mut r = rng.gen::<f64>();
+        for (hp, p) in self.0.iter().enumerate() {
+            if r < *p {
+                return hp as i32;
+            }
+            r -= p;
+        }
+        self.0.len() as i32 - 1
+    }
+    pub fn damaged(&self, dam: &DamagePmf) -> HpDist {
+        let mut res = vec![0.0; self.0.len()];
+        res[0] = self.0[0];
+        for (hp, p) in self.0.iter().enumerate().skip(1) {
+            for (d, q) in dam.0.iter().enumerate() {
+                res[hp.saturating_sub(d)] += p * q;
+            }
+        }
+        HpDist(res)
+    }
+    pub fn death_prob(&self) -> f64 {
+        self.0[0]
+    }
+    // キャッシュのキーに使う
+    pub fn to_bits(&self) -> Vec<u64> {
+        self.0.iter().map(|p| p.to_bits()).collect()
+    }
+    // k回攻撃して倒せている確率
+    pub fn kill_prob(&self, swing: &DamagePmf, k: usize) -> f64 {
+        let sum = swing.repeat(k);
+        self.0
+            .iter()
+            .enumerate()
+            .fold(0.0, |acc, (hp, p)| acc + p * sum.at_least(hp as i32))
+    }
+    pub fn expect_val(&self) -> DamageVal {
+        let sum = self.0
+            .iter()
+            .enumerate()
+            .fold(0.0, |acc, (i, p)| acc + i as f64 * p);
+        DamageVal(sum)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FightResult {
+    pub win: f64,
+    pub death: f64,
+}
+
+// 1対1でプレイヤーが先に殴り、交互に攻撃し合ったとき、turnsターン以内に勝つ(死ぬ)確率
+// attack, deffenceは外れも含めた1ターン分のダメージ
+pub fn eval_fight(
+    player_hp: &HpDist,
+    attack: &DamagePmf,
+    enem_hp: &HpDist,
+    deffence: &DamagePmf,
+    turns: usize,
+) -> FightResult {
+    let (php, ehp) = (player_hp.0.len() - 1, enem_hp.0.len() - 1);
+    let mut res = FightResult::default();
+    res.death = player_hp.death_prob();
+    res.win = enem_hp.death_prob() * (1.0 - res.death);
+    // state[p][e]: 両方生きていてHPがp, eである確率
+    let mut state = vec![vec![0.0; ehp + 1]; php + 1];
+    for (p, &pp) in player_hp.0.iter().enumerate().skip(1) {
+        for (e, &pe) in enem_hp.0.iter().enumerate().skip(1) {
+            state[p][e] = pp * pe;
+        }
+    }
+    for _ in 0..turns {
+        let mut next = vec![vec![0.0; ehp + 1]; php + 1];
+        for (p, row) in state.iter().enumerate().skip(1) {
+            for (e, &s) in row.iter().enumerate().skip(1).filter(|&(_, &s)| s > 0.0) {
+                for (d, q) in attack.0.iter().enumerate() {
+                    if d >= e {
+                        res.win += s * q;
+                    } else {
+                        next[p][e - d] += s * q;
+                    }
+                }
+            }
+        }
+        state = vec![vec![0.0; ehp + 1]; php + 1];
+        for (p, row) in next.iter().enumerate().skip(1) {
+            for (e, &s) in row.iter().enumerate().skip(1).filter(|&(_, &s)| s > 0.0) {
+                for (d, q) in deffence.0.iter().enumerate() {
+                    if d >= p {
+                        res.death += s * q;
+                    } else {
+                        state[p - d][e] += s * q;
+                    }
+                }
+            }
+        }
+    }
+    res
+}
+
+// 隣の敵と殴り合ったときの結果 敵は起きているものとする
+pub fn eval_fight_enemy(
+    profile: &CombatProfile,
+    player_hp: &HpDist,
+    enem: &EnemyHist,
+    turns: usize,
+) -> FightResult {
+    let wield = profile.wield;
+    let attack = profile.dam_pmf(wield).with_hit(profile.hit_rate(wield, enem));
+    let deffence = profile.dam_pmf_deffence(enem.typ);
+    eval_fight(player_hp, &attack, &enem.hp, &deffence, turns)
+}
+
+// モンテカルロで殴り合いを再現した結果
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimResult {
+    pub win_rate: f64,
+    pub death_rate: f64,
+    pub turns: f64,   // 勝ったときに倒すまでにかかったターン数の平均
+    pub hp_lost: f64, // 失ったHPの平均
+}
+
+// eval_fightと同じ条件(プレイヤーが先攻、敵は起きている)でtrials回殴り合う
+pub fn simulate_fight(
+    profile: &CombatProfile,
+    player_hp: i32,
+    enem: &EnemyHist,
+    turns: usize,
+    trials: usize,
+) -> SimResult {
+    let mut rng = thread_rng();
+    let hit_attack = *profile.hit_rate(profile.wield, enem);
+    let hit_deffence = *profile.hit_rate_deffence(enem.typ);
+    let (dice, _, dplus) = profile.attack_dice(profile.wield);
+    let plus = f64::from(dplus);
+    let mut res = SimResult::default();
+    let (mut wins, mut deaths, mut win_turns, mut lost) = (0, 0, 0, 0.0);
+    for _ in 0..trials {
+        let mut php = f64::from(player_hp);
+        let mut ehp = f64::from(enem.hp.sample(&mut rng));
+        for turn in 0..turns {
+            if rng.gen::<f64>() < hit_attack {
+                ehp -= (*dice.random_val() + plus).max(0.0);
+            }
+            if ehp <= 0.0 {
+                wins += 1;
+                win_turns += turn + 1;
+                break;
+            }
+            for dice in enem.typ.attack() {
+                if rng.gen::<f64>() < hit_deffence {
+                    php -= *dice.random_val();
+                }
+            }
+            if php <= 0.0 {
+                deaths += 1;
+                break;
+            }
+        }
+        lost += f64::from(player_hp) - php.max(0.0);
+    }
+    let trials_f = trials as f64;
+    res.win_rate = f64::from(wins) / trials_f;
+    res.death_rate = f64::from(deaths) / trials_f;
+    if wins > 0 {
+        res.turns = win_turns as f64 / f64::from(wins);
+    }
+    res.hp_lost = lost / trials_f;
+    res
+}
+
+// --simulateで使う 初期状態のプレイヤーがHPごとに戦った結果と解析的な見積もりを並べる
+pub fn print_fight_report(enem: Enemy, turns: usize, trials: usize) {
+    let player = PlayerStatus::initial();
+    let profile = CombatProfile::initial();
+    let mut hist = EnemyHist::from_type(enem);
+    hist.wake();
+    println!("{:?} hp: {:.2} turns: {} trials: {}", enem, *hist.hp_ex, turns, trials);
+    println!(" hp    win  death  turns  hp_lost | est_win est_death");
+    for hp in (1..player.max_hp + 1).filter(|hp| hp % 2 == 0) {
+        let sim = simulate_fight(&profile, hp, &hist, turns, trials);
+        let est = eval_fight_enemy(&profile, &HpDist::new(hp), &hist, turns);
+        println!(
+            "{:3} {:6.3} {:6.3} {:6.2} {:8.2} | {:7.3} {:9.3}",
+            hp, sim.win_rate, sim.death_rate, sim.turns, sim.hp_lost, est.win, est.death
+        );
     }
 }
 
@@ -159,12 +549,177 @@ mod test {
         assert_approx_eq!(*Dice::new(3, 6).expect_val(), 10.5);
         let v = vec![Dice::new(1, 6), Dice::new(1, 5)];
         assert_approx_eq!(*v.expect_val(), 6.5);
+        assert_approx_eq!(*v.max_val(), 11.0);
+    }
+    #[test]
+    fn test_pmf() {
+        let pmf = Dice::new(2, 4).pmf();
+        assert_approx_eq!(pmf.0[2], 1.0 / 16.0);
+        assert_approx_eq!(pmf.0[5], 4.0 / 16.0);
+        assert_approx_eq!(*pmf.expect_val(), *Dice::new(2, 4).expect_val());
+        assert_approx_eq!(pmf.at_least(8), 1.0 / 16.0);
+        assert_approx_eq!(pmf.at_least(0), 1.0);
+        let v = vec![Dice::new(1, 6), Dice::new(1, 5)];
+        assert_approx_eq!(*v.pmf().expect_val(), 6.5);
+        // 力の補正でマイナスになっても0ダメージ
+        let weak = Dice::new(1, 4).pmf().plus(-2);
+        assert_approx_eq!(weak.0[0], 0.5);
+        assert_approx_eq!(weak.at_least(2), 0.25);
+        // 外れることもある攻撃は回数を重ねると倒しやすい
+        let swing = pmf.with_hit(ProbVal(0.5));
+        assert_approx_eq!(swing.at_least(1), 0.5);
+        let hp = HpDist::new(8);
+        assert!(hp.kill_prob(&swing, 1) < hp.kill_prob(&swing, 3));
+        assert_approx_eq!(HpDist::new(0).kill_prob(&swing, 0), 1.0);
+        // Bat(1d2)に2回とも外されるのは(1-命中率)^2
+        let prof = CombatProfile::initial();
+        let miss = 1.0 - *prof.hit_rate_deffence(Enemy::Bat);
+        assert_approx_eq!(prof.dam_pmf_deffence(Enemy::Bat).repeat(2).0[0], miss * miss);
+    }
+    #[test]
+    fn test_fight_eval() {
+        let hp = HpDist::new(4).damaged(&Dice::new(1, 8).pmf());
+        assert_approx_eq!(hp.death_prob(), 5.0 / 8.0);
+        assert_approx_eq!(*hp.expect_val(), 0.75);
+        // 必ず1ダメージずつ与え合うなら、先に殴る方が勝つ
+        let one = DamagePmf::constant(1);
+        let res = eval_fight(&HpDist::new(2), &one, &HpDist::new(2), &one, 10);
+        assert_approx_eq!(res.win, 1.0);
+        assert_approx_eq!(res.death, 0.0);
+        let res = eval_fight(&HpDist::new(2), &one, &HpDist::new(3), &one, 1);
+        assert_approx_eq!(res.win + res.death, 0.0);
+        // HP4のプレイヤーはHobgoblin(1d8)に一撃で殺されうる
+        let prof = CombatProfile::initial();
+        let mut hob = EnemyHist::from_type(Enemy::Hobgoblin);
+        hob.wake();
+        let weak = eval_fight_enemy(&prof, &HpDist::new(4), &hob, 10);
+        let strong = eval_fight_enemy(&prof, &HpDist::new(12), &hob, 10);
+        assert!(weak.death > 0.2 && weak.death > strong.death);
+        assert!(weak.win + weak.death <= 1.0 + 1e-9);
+    }
+    #[test]
+    fn test_simulate_fight() {
+        // 解析的な見積もりとモンテカルロの結果が合うか確かめる
+        const TRIALS: usize = 20000;
+        const TURNS: usize = 10;
+        let prof = CombatProfile::initial();
+        let cases = [
+            (Enemy::Hobgoblin, 4),
+            (Enemy::Hobgoblin, 12),
+            (Enemy::Emu, 12),
+            (Enemy::Orc, 8),
+            (Enemy::Aquator, 12),
+        ];
+        for &(typ, hp) in &cases {
+            let mut enem = EnemyHist::from_type(typ);
+            enem.wake();
+            let sim = simulate_fight(&prof, hp, &enem, TURNS, TRIALS);
+            let est = eval_fight_enemy(&prof, &HpDist::new(hp), &enem, TURNS);
+            assert!((sim.win_rate - est.win).abs() < 0.03, "{:?} {:?} {:?}", typ, sim, est);
+            assert!((sim.death_rate - est.death).abs() < 0.03, "{:?} {:?} {:?}", typ, sim, est);
+            assert!(sim.hp_lost <= f64::from(hp));
+        }
+        // 死なないプレイヤーなら、倒すまでのターン数はkill_probと合う
+        let mut hob = EnemyHist::from_type(Enemy::Hobgoblin);
+        hob.wake();
+        let sim = simulate_fight(&prof, 10000, &hob, 1, TRIALS);
+        let swing = prof.dam_pmf(prof.wield).with_hit(prof.hit_rate(prof.wield, &hob));
+        let hp = &hob.hp;
+        assert!((sim.win_rate - hp.kill_prob(&swing, 1)).abs() < 0.03);
+        let sim = simulate_fight(&prof, 10000, &hob, TURNS, TRIALS);
+        let expect_turns = (0..TURNS).fold(0.0, |acc, k| {
+            acc + (k + 1) as f64 * (hp.kill_prob(&swing, k + 1) - hp.kill_prob(&swing, k))
+        }) / hp.kill_prob(&swing, TURNS);
+        assert!((sim.turns - expect_turns).abs() < 0.1, "{:?} {}", sim, expect_turns);
+    }
+    #[test]
+    fn test_enemy_hp() {
+        let prof = CombatProfile::initial();
+        let swing = prof.dam_pmf(prof.wield);
+        // Troll(6d8)は殴られても、時間がたつと回復して倒すのに必要な手数が増える
+        let mut troll = EnemyHist::from_type(Enemy::Troll);
+        troll.hit(&swing);
+        troll.hit(&swing);
+        troll.seen_alive();
+        assert_approx_eq!(troll.hp.death_prob(), 0.0);
+        let hurt = troll.clone();
+        troll.regenerate(5);
+        assert!(*troll.hp_ex > *hurt.hp_ex + 4.0);
+        assert!(troll.hp.kill_prob(&swing, 3) < hurt.hp.kill_prob(&swing, 3));
+        // 与えた以上には回復しない
+        let healed = troll.hp_ex;
+        troll.regenerate(1000);
+        assert!(troll.dealt < 1.0);
+        assert!(*troll.hp_ex < *healed + *swing.expect_val() * 2.0 - 4.0);
+        // 回復しない敵はそのまま
+        let mut hob = EnemyHist::from_type(Enemy::Hobgoblin);
+        hob.hit(&swing);
+        let hurt = hob.clone();
+        hob.regenerate(5);
+        assert_approx_eq!(*hob.hp_ex, *hurt.hp_ex);
+        // 倒れていないならHP0の可能性は消える
+        assert!(hob.hp.death_prob() > 0.0);
+        hob.seen_alive();
+        assert!(hob.is_live() && hob.hp.death_prob() == 0.0);
+    }
+    #[test]
+    fn test_combat_profile() {
+        let mut prof = CombatProfile::initial();
+        let arrow = Attack::new(Weapon::Arrow, (0, 0), true);
+        // 弓なしで投げた矢は1d1
+        assert_approx_eq!(*prof.expect_dam(arrow), 1.0);
+        // 弓で射れば2d3に弓の補正が乗る
+        prof.wield = Attack::new(Weapon::Bow, (1, 2), false);
+        assert_approx_eq!(*prof.expect_dam(arrow), 6.0);
+        // 指輪は手に持った武器にだけ効く
+        prof.wield = Attack::new(Weapon::Mace, (1, 1), false);
+        let mace = prof.expect_dam(prof.wield);
+        let dagger = Attack::new(Weapon::Dagger, (0, 0), true);
+        let thrown = prof.expect_dam(dagger);
+        prof.ring_plus = (2, 3);
+        assert_approx_eq!(*prof.expect_dam(prof.wield), *mace + 3.0);
+        assert_approx_eq!(*prof.expect_dam(dagger), *thrown);
+        let ene = EnemyHist::from_type(Enemy::Emu);
+        assert!(prof.hit_rate(prof.wield, &ene) > prof.hit_rate(dagger, &ene));
     }
     #[test]
     fn test_hit_rate() {
-        let player = PlayerStatus::initial();
+        let prof = CombatProfile::initial();
         let ene = EnemyHist::from_type(Enemy::Emu);
-        println!("{:?}", hit_rate_attack(&player, &ene));
-        println!("{:?}", hit_rate_deffence(&player, &ene.typ));
+        println!("{:?}", prof.hit_rate(prof.wield, &ene));
+        println!("{:?}", prof.hit_rate_deffence(ene.typ));
+    }
+    #[test]
+    fn test_wake() {
+        let prof = CombatProfile::initial();
+        // Hobgoblinは近づくと起きることがある
+        let mut ene = EnemyHist::from_type(Enemy::Hobgoblin);
+        let asleep = prof.hit_rate(prof.wield, &ene);
+        ene.disturbed();
+        assert!((ene.awake_prob() - 2.0 / 3.0).abs() < 1e-6);
+        let half = prof.hit_rate(prof.wield, &ene);
+        ene.stayed();
+        assert!(ene.awake_prob() < 2.0 / 3.0);
+        ene.wake();
+        let awake = prof.hit_rate(prof.wield, &ene);
+        assert!(asleep > half && half > awake);
+        // MEANでない敵は勝手に起きない
+        let mut bat = EnemyHist::from_type(Enemy::Bat);
+        bat.disturbed();
+        assert_eq!(bat.awake_prob(), 0.0);
+    }
+    #[test]
+    fn test_level_danger() {
+        use data::appear_prob;
+        // 浅い階ではKEBSHしか出ない
+        let first = appear_prob(1, false);
+        assert_eq!(first.len(), 5);
+        assert!((first.iter().map(|&(_, p)| p).sum::<f64>() - 1.0).abs() < 1e-6);
+        // Ice Monsterはさまよってこない
+        let ice = |wander| appear_prob(6, wander).iter().any(|&(e, _)| e == Enemy::IceMonster);
+        assert!(ice(false) && !ice(true));
+        let prof = CombatProfile::initial();
+        let danger = |lv| *level_danger(&prof, lv);
+        assert!(danger(1) < danger(10) && danger(10) < danger(20));
     }
 }
@@ -3,7 +3,7 @@ use consts::*;
 use damage::ProbVal;
 use data::*;
 use std::cmp::{self, Ordering};
-use std::collections::VecDeque;
+use std::collections::{BinaryHeap, VecDeque};
 use std::fmt::Debug;
 use std::mem;
 use std::ops::{Add, AddAssign, Sub, SubAssign};
@@ -19,6 +19,7 @@ bitflags! {
         const RIGHT_UP   = 0b001000000;
         const LEFT_DOWN  = 0b010000000;
         const RIGHT_DOWN = 0b100000000;
+        const DETECTED   = 0b1000000000;
     }
 }
 
@@ -43,13 +44,23 @@ pub struct ExplHist {
     searched: u32,
 }
 
+// 最後に見たアイテムとそのターン
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ItemMemory {
+    pub item: Item,
+    pub turn: usize,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Cell {
     obj: FieldObject,
     surface: Surface,
     hist: ExplHist,
+    memory: Option<ItemMemory>, // 画面から消えても覚えておくアイテム
 }
 
+// 敵がいそうなマスを通るときの追加コスト(確率1のとき)
+const DANGER_COST: f64 = 10.0;
 const FIND_RATE_DOOR: f64 = 0.19;
 const FIND_RATE_ROAD: f64 = 0.49;
 
@@ -62,15 +73,18 @@ impl Cell {
         self.hist.attr.insert(ExplAttr::VISITED);
     }
 
+    // 見てはいないが魔法の地図や探知で分かっている
+    pub fn is_detected(&self) -> bool {
+        self.hist.attr.contains(ExplAttr::DETECTED)
+    }
+
+    fn detect(&mut self) {
+        self.hist.attr.insert(ExplAttr::DETECTED);
+    }
+
     // 隠し通路があった場合に「それが見つかっていない」確率
-    pub fn search_suc_rate(&self) -> f64 {
-        let searched_i = self.hist.searched as i32;
-        let val = if self.surface == Surface::Road {
-            (1.0 - FIND_RATE_ROAD).powi(searched_i)
-        } else {
-            (1.0 - FIND_RATE_DOOR).powi(searched_i)
-        };
-        val
+    pub fn not_found_rate(&self, find_rate: f64) -> f64 {
+        (1.0 - find_rate).powi(self.hist.searched as i32)
     }
 
     pub fn moved(&mut self, d: Direc) {
@@ -98,6 +112,27 @@ impl Cell {
     pub fn surface(&self) -> Surface {
         self.surface
     }
+    pub fn memory(&self) -> Option<ItemMemory> {
+        self.memory
+    }
+    // 画面の文字からアイテムの記憶を更新する
+    // 幻覚中はアイテムの種類がでたらめなので、既に覚えているものは種類を更新しない
+    fn remember(&mut self, c: u8, turn: usize, hallu: bool) {
+        match self.obj {
+            FieldObject::Item(item) => match self.memory {
+                Some(ref mut mem) if hallu => mem.turn = turn,
+                _ => self.memory = Some(ItemMemory { item, turn }),
+            },
+            // 空のマスが見えたら忘れる
+            FieldObject::None if Surface::from(c) != Surface::None => self.memory = None,
+            // 敵やプレイヤーの下にあるかもしれないので覚えておく
+            _ => {}
+        }
+    }
+    // 階段でないと分かっているマスかどうか
+    fn known_not_stair(&self) -> bool {
+        self.memory.is_some() || (self.surface != Surface::None && self.surface != Surface::Stair)
+    }
     pub fn need_guess(&self) -> bool {
         match self.surface {
             Surface::Stair | Surface::Trap => true,
@@ -130,12 +165,17 @@ default_none!(DangeonMsg);
 #[derive(Debug, Clone)]
 pub struct Dangeon {
     inner: Vec<Vec<Cell>>,
+    hallucinating: bool, // 幻覚中は物体の文字を信用しない
+    mapped: bool,        // 魔法の地図を読んだ(隠し扉も全て見えている)
 }
 
 impl Default for Dangeon {
     fn default() -> Dangeon {
+        let geo = geometry();
         Dangeon {
-            inner: vec![vec![Cell::default(); COLUMNS]; LINES],
+            inner: vec![vec![Cell::default(); geo.columns]; geo.map_lines()],
+            hallucinating: false,
+            mapped: false,
         }
     }
 }
@@ -153,15 +193,22 @@ impl Dangeon {
         }
     }
 
-    pub fn merge(&mut self, orig: &[Vec<u8>]) -> DangeonMsg {
+    pub fn merge(&mut self, orig: &[Vec<u8>], turn: usize) -> DangeonMsg {
         let mut res = DangeonMsg::default();
         let mut new_floor = None;
+        let hallu = self.hallucinating;
         for (cell_mut, cd) in self.iter_mut() {
             let c = orig[cd.y as usize][cd.x as usize];
             if c == b'\\' {
                 return DangeonMsg::Die;
             }
+            // 幻覚中はアイテムが階段の文字で描かれることがある
+            if hallu && c == b'%' && cell_mut.known_not_stair() {
+                cell_mut.obj = FieldObject::None;
+                continue;
+            }
             cell_mut.obj = FieldObject::from(c);
+            cell_mut.remember(c, turn, hallu);
             let cur_surface = Surface::from(c);
             if cur_surface != Surface::None {
                 if cell_mut.surface == Surface::None {
@@ -183,10 +230,106 @@ impl Dangeon {
         res
     }
 
+    pub fn set_hallucination(&mut self, hallu: bool) {
+        self.hallucinating = hallu;
+    }
+
+    // 投げた武器など、見えていなくても落ちたはずのアイテムを覚えておく
+    pub fn drop_item(&mut self, cd: Coord, item: Item, turn: usize) {
+        if let Some(cell) = self.get_mut(cd) {
+            if cell.memory.is_none() {
+                cell.memory = Some(ItemMemory { item, turn });
+            }
+        }
+    }
+
+    // アイテムを拾った場合など
+    pub fn forget_item(&mut self, cd: Coord) {
+        if let Some(cell) = self.get_mut(cd) {
+            cell.memory = None;
+        }
+    }
+
     pub fn init(&mut self) {
         for (cell_mut, _) in self.iter_mut() {
             *cell_mut = Cell::default();
         }
+        self.mapped = false;
+    }
+
+    // 魔法の地図や探知の結果を、見てはいないが確かな情報として取り込む
+    pub fn merge_detected(&mut self, orig: &[Vec<u8>], turn: usize, kind: Detection) -> DangeonMsg {
+        match kind {
+            Detection::Map => {
+                let res = self.merge(orig, turn);
+                self.fill_rooms();
+                // 階全体が分かったので、訪れていない場所を探索する必要はない
+                for (cell, _) in self.iter_mut() {
+                    if cell.surface != Surface::None && !cell.is_visited() {
+                        cell.detect();
+                    }
+                }
+                self.mapped = true;
+                res
+            }
+            Detection::Magic | Detection::Food => {
+                // 探知の結果は別のウィンドウに一瞬だけ描かれ、アイテム以外は写らない
+                for (cell, cd) in self.iter_mut() {
+                    let c = orig[cd.y as usize][cd.x as usize];
+                    let item = if c == b'$' { Item::Magic } else { Item::from(c) };
+                    if item == Item::None {
+                        continue;
+                    }
+                    if cell.memory.is_none() {
+                        cell.memory = Some(ItemMemory { item, turn });
+                    }
+                    if !cell.is_visited() {
+                        cell.detect();
+                    }
+                }
+                DangeonMsg::None
+            }
+            // 探知した敵はEnemyListで覚える
+            Detection::Monster => DangeonMsg::None,
+        }
+    }
+
+    // 魔法の地図では床が描かれないので、壁で囲まれた内側を床とみなす
+    fn fill_rooms(&mut self) {
+        let rooms: Vec<_> = self.iter()
+            .filter_map(|(_, cd)| self.room_from_corner(cd))
+            .collect();
+        for rect in rooms {
+            for (cell, cd) in self.rect_iter_mut(rect) {
+                if rect.check_pos(cd) == RectPos::In && cell.surface == Surface::None {
+                    cell.surface = Surface::Floor;
+                }
+            }
+        }
+    }
+
+    // cdを左上の角とする部屋
+    fn room_from_corner(&self, cd: Coord) -> Option<Rect> {
+        let is_edge = |cd: Coord| {
+            self.get(cd)
+                .is_some_and(|c| c.surface == Surface::Wall || c.surface == Surface::Door)
+        };
+        let (right, down) = (Direc::Right.to_cd(), Direc::Down.to_cd());
+        if !is_edge(cd) || is_edge(cd - right) || is_edge(cd - down) {
+            return None;
+        }
+        if !is_edge(cd + right) || !is_edge(cd + down) {
+            return None;
+        }
+        let mut r = cd;
+        while is_edge(r + right) {
+            r += right;
+        }
+        let mut b = cd;
+        while is_edge(b + down) {
+            b += down;
+        }
+        Rect::new(cd, Coord::new(r.x, b.y))
     }
 
     pub fn iter(&self) -> CoordIter<Dangeon> {
@@ -290,7 +433,13 @@ impl Dangeon {
         }
     }
 
-    fn can_move_sub(cur: Surface, nxt: Surface, d: Direc, is_enemy: bool) -> Option<bool> {
+    fn can_move_sub(
+        cur: Surface,
+        nxt: Surface,
+        d: Direc,
+        is_enemy: bool,
+        diag_ok: bool,
+    ) -> Option<bool> {
         match cur {
             Surface::Floor => match nxt {
                 Surface::Floor | Surface::Stair | Surface::Trap => Some(true),
@@ -299,9 +448,11 @@ impl Dangeon {
                 _ => None,
             },
             Surface::Road => match nxt {
-                Surface::Road | Surface::Stair | Surface::Trap | Surface::Door => {
-                    Some(!d.is_diag() || is_enemy)
+                // 迷路や通路の交差点では斜めにも進める
+                Surface::Road | Surface::Stair | Surface::Trap => {
+                    Some(!d.is_diag() || diag_ok || is_enemy)
                 }
+                Surface::Door => Some(!d.is_diag() || is_enemy),
                 Surface::Wall => Some(false),
                 _ => None,
             },
@@ -338,7 +489,19 @@ impl Dangeon {
         } else {
             nxt_cell.surface
         };
-        Dangeon::can_move_sub(cur_sur, nxt_sur, d, is_enemy)
+        let diag_ok = d.is_diag() && self.diag_ok(cd, d);
+        Dangeon::can_move_sub(cur_sur, nxt_sur, d, is_enemy, diag_ok)
+    }
+
+    // rogueのdiag_okと同じく、斜め移動は両脇のマスが通れるときだけ許す
+    fn diag_ok(&self, cd: Coord, d: Direc) -> bool {
+        let dcd = d.to_cd();
+        [Coord::new(dcd.x, 0), Coord::new(0, dcd.y)]
+            .iter()
+            .all(|&side| {
+                self.get(cd + side)
+                    .is_some_and(|c| c.surface != Surface::None && c.surface != Surface::Wall)
+            })
     }
 
     pub fn can_move(&self, cd: Coord, d: Direc) -> bool {
@@ -370,6 +533,61 @@ impl Dangeon {
         Some(dist)
     }
 
+    // 危険度を移動コストに加えた距離(Dijkstra)
+    // 危険なマスから出るときにコストがかかるので、値が小さくなる方へ進めばよい
+    pub fn make_cost_map(&self, start: Coord, danger: &SimpleMap<f64>) -> Option<SimpleMap<i32>> {
+        let mut dist = SimpleMap::new(INF_DIST);
+        *dist.get_mut(start)? = 0;
+        let mut que = BinaryHeap::new();
+        que.push((cmp::Reverse(0), start.x, start.y));
+        while let Some((cmp::Reverse(cur_dist), x, y)) = que.pop() {
+            let cd = Coord::new(x, y);
+            if cur_dist > *dist.get(cd)? {
+                continue;
+            }
+            for &d in Direc::vars().take(8) {
+                let nxt_cd = cd + d.to_cd();
+                if !self.can_move(cd, d) {
+                    continue;
+                }
+                let cost = 1 + (danger.get(nxt_cd)? * DANGER_COST).round() as i32;
+                if let Some(nxt_dist_ref) = dist.get_mut(nxt_cd) {
+                    if cur_dist + cost < *nxt_dist_ref {
+                        *nxt_dist_ref = cur_dist + cost;
+                        que.push((cmp::Reverse(cur_dist + cost), nxt_cd.x, nxt_cd.y));
+                    }
+                }
+            }
+        }
+        Some(dist)
+    }
+
+    // 敵がstartからsteps歩以内で行けるマス
+    pub fn enemy_reach(&self, start: Coord, steps: i32) -> Vec<Coord> {
+        let mut dist = SimpleMap::new(INF_DIST);
+        *or_empty_vec!(dist.get_mut(start)) = 0;
+        let mut que = VecDeque::new();
+        que.push_back(start);
+        let mut res = vec![start];
+        while let Some(cd) = que.pop_front() {
+            let cur_dist = *or_empty_vec!(dist.get(cd));
+            if cur_dist >= steps {
+                continue;
+            }
+            for &d in Direc::vars().take(8) {
+                let nxt_cd = cd + d.to_cd();
+                if let Some(nxt_dist_ref) = dist.get_mut(nxt_cd) {
+                    if *nxt_dist_ref == INF_DIST && self.can_move_enemy(cd, d) {
+                        *nxt_dist_ref = cur_dist + 1;
+                        que.push_back(nxt_cd);
+                        res.push(nxt_cd);
+                    }
+                }
+            }
+        }
+        res
+    }
+
     pub fn recover(&self, cd: Coord) -> Option<Direc> {
         for &d in Direc::vars() {
             if self.can_move(cd, d) {
@@ -400,36 +618,79 @@ impl Dangeon {
                 acc
             }
         });
-        let all = LINES * COLUMNS;
+        let geo = geometry();
+        let all = geo.map_lines() * geo.columns;
         ProbVal(known / all as f64)
     }
 
-    fn find_not_visited(&self) -> Vec<Coord> {
-        self.iter()
+    // 未訪問で、周りに未知のマスがあるマス
+    fn is_frontier(&self, cd: Coord) -> bool {
+        match self.get(cd) {
+            Some(cell) => {
+                !cell.is_visited() && !cell.is_detected() && cell.surface != Surface::None
+                    && self.count_around_none(cd) > 0
+            }
+            None => false,
+        }
+    }
+
+    // 巡回計画の次の目的地とその評価値
+    pub fn explore(&self, dist: &SimpleMap<i32>, route: &RoutePlan) -> Option<(Coord, ActionVal)> {
+        let goal = route.next_goal()?;
+        let dis = *dist.get(goal.cd).unwrap_or(&INF_DIST);
+        let val = match goal.kind {
+            GoalKind::Frontier => ActionVal::not_visited(self.count_around_none(goal.cd)),
+            GoalKind::Item => ActionVal::from_item(self.get(goal.cd)?.memory?.item),
+            GoalKind::Stair => return None,
+        };
+        Some((goal.cd, val.comp_dist(dis)))
+    }
+
+    // 巡回の候補(未探索の境界・覚えているアイテム・階段)を近い順に集める
+    // 近すぎる同種の候補は1つにまとめる
+    fn route_goals(&self, dist: &SimpleMap<i32>) -> Vec<Goal> {
+        let mut cands: Vec<_> = self.iter()
             .filter_map(|(cell, cd)| {
-                if !cell.is_visited() && cell.surface != Surface::None {
-                    Some(cd)
-                } else {
-                    None
+                let dis = *dist.get(cd)?;
+                if dis == INF_DIST || cell.obj == FieldObject::Player {
+                    return None;
                 }
+                let kind = if cell.memory.is_some() {
+                    GoalKind::Item
+                } else if self.is_frontier(cd) {
+                    GoalKind::Frontier
+                } else {
+                    return None;
+                };
+                Some((dis, Goal { cd, kind }))
             })
-            .collect()
+            .collect();
+        cands.sort_by_key(|&(dis, goal)| (dis, goal.cd));
+        let mut res: Vec<Goal> = Vec::new();
+        for (_, goal) in cands {
+            if res.len() >= ROUTE_GOAL_MAX {
+                break;
+            }
+            if !res.iter().any(|g| g.is_near(&goal)) {
+                res.push(goal);
+            }
+        }
+        if let Some(cd) = self.find_stair() {
+            res.push(Goal {
+                cd,
+                kind: GoalKind::Stair,
+            });
+        }
+        res
     }
-    //  0 | 1 | 2
-    //  -   -   -
-    //  3 | 4 | 5
-    //  -   -   -
-    //  6 | 7 | 8
-    pub fn explore(&self, dist: &SimpleMap<i32>) -> Option<(Coord, ActionVal)> {
-        let not_visited = self.find_not_visited();
-        let mut non_visited_val = ActionVal::default();
-        let non_visited_cd = not_visited.iter().max_by_key(|&&cd| {
-            let dis = *dist.get(cd).unwrap_or(&INF_DIST);
-            let val = ActionVal::not_visited(self.count_around_none(cd)).comp_dist(dis);
-            non_visited_val = cmp::max(non_visited_val, val);
-            val
-        })?;
-        Some((non_visited_cd.clone(), non_visited_val))
+
+    fn is_valid_goal(&self, goal: &Goal) -> bool {
+        match goal.kind {
+            GoalKind::Frontier => self.is_frontier(goal.cd),
+            GoalKind::Item => self.get(goal.cd).is_some_and(|c| c.memory.is_some()),
+            GoalKind::Stair => self.get(goal.cd)
+                .is_some_and(|c| c.surface == Surface::Stair),
+        }
     }
 
     // BFSして葉がdead_endかどうか判断する
@@ -481,135 +742,538 @@ impl Dangeon {
         res
     }
 
-    // 各壁について最も探索回数の少ないマスを適当に集めて返す
-    fn find_walls(&self, start: Coord) -> Vec<(Coord, Direc)> {
-        let mut res = Vec::new();
-        let mut used = SimpleMap::new(false);
-        *or_empty_vec!(used.get_mut(start)) = true;
-        let mut que = VecDeque::new();
-        que.push_back(start);
-        while let Some(cd) = que.pop_front() {
-            if *or_empty_vec!(used.get(cd)) {
-                continue;
+    // searchコマンドを実行した座標の周囲の探索回数を記録する
+    pub fn searched(&mut self, cd: Coord) {
+        for &d in Direc::vars() {
+            if let Some(cell) = self.get_mut(cd + d.to_cd()) {
+                cell.hist.searched += 1;
             }
-            for (i, &d) in Direc::vars().take(8).enumerate() {
-                let nxt_cd = cd + d.to_cd();
-                if self.can_move(cd, d) {
-                    if let Some(nxt_used_ref) = used.get_mut(nxt_cd) {
-                        if !*nxt_used_ref {
-                            que.push_back(nxt_cd);
-                            *nxt_used_ref = true;
-                        }
+        }
+    }
+
+    // 隠し通路の事後確率から、どこで何回searchするかを決める
+    pub fn search(
+        &self,
+        dist: &SimpleMap<i32>,
+        player_cd: Coord,
+    ) -> Option<(Coord, ActionVal, u32)> {
+        let secret = secret::SecretMap::new(self, player_cd);
+        self.iter()
+            .filter_map(|(_, cd)| {
+                let dis = *dist.get(cd)?;
+                if dis == INF_DIST {
+                    return None;
+                }
+                let (val, turns) = secret.search_val(cd);
+                if turns == 0 {
+                    None
+                } else {
+                    Some((cd, val.comp_dist(dis), turns))
+                }
+            })
+            .max_by_key(|&(_, val, _)| val)
+    }
+
+    pub fn find_stair(&self) -> Option<Coord> {
+        let cd = self.iter()
+            .find(|&cell_cd| cell_cd.0.surface == Surface::Stair)?;
+        Some(cd.1)
+    }
+
+    // 距離が同じなら最近見たものを優先する
+    pub fn find_nearest_item(&self, dist: &SimpleMap<i32>) -> Option<(Coord, ActionVal)> {
+        let (memory, cd) = self.iter()
+            .filter(|&(cell, _)| cell.obj != FieldObject::Player)
+            .filter_map(|(cell, cd)| Some((cell.memory?, cd)))
+            .min_by_key(|&(memory, cd)| {
+                (*dist.get(cd).unwrap_or(&0), cmp::Reverse(memory.turn))
+            })?;
+        Some((cd, ActionVal::from_item(memory.item)))
+    }
+}
+
+const ROUTE_GOAL_MAX: usize = 16;
+// これ以上近い同種の目的地は同じものとみなす
+const ROUTE_MERGE_DIST: i32 = 3;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GoalKind {
+    Frontier,
+    Item,
+    Stair,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Goal {
+    pub cd: Coord,
+    pub kind: GoalKind,
+}
+
+impl Goal {
+    fn is_near(&self, other: &Goal) -> bool {
+        self.kind == other.kind && self.cd.dist_cheb(&other.cd) <= ROUTE_MERGE_DIST
+    }
+}
+
+// 探索の巡回計画
+// 新しい目的地が現れない限り順番を変えないことで、部屋の間を行ったり来たりするのを防ぐ
+#[derive(Clone, Debug, Default)]
+pub struct RoutePlan {
+    goals: Vec<Goal>,
+}
+
+impl RoutePlan {
+    pub fn init(&mut self) {
+        self.goals.clear();
+    }
+
+    pub fn update(&mut self, dangeon: &Dangeon, start: Coord, dist: &SimpleMap<i32>) {
+        self.goals.retain(|g| {
+            dangeon.is_valid_goal(g) && dist.get(g.cd).is_some_and(|&d| d != INF_DIST)
+        });
+        let goals = dangeon.route_goals(dist);
+        let has_new = goals
+            .iter()
+            .any(|g| !self.goals.iter().any(|planned| planned.is_near(g)));
+        if has_new {
+            self.goals = plan_tour(dangeon, start, goals);
+            debug!(LOGGER, "new route plan: {:?}", self.goals);
+        }
+    }
+
+    // 階段は最後に行くので返さない
+    pub fn next_goal(&self) -> Option<Goal> {
+        self.goals
+            .iter()
+            .find(|g| g.kind != GoalKind::Stair)
+            .cloned()
+    }
+}
+
+// startから全ての目的地を回る短い経路を求める(貪欲法 + 2-opt)
+// 階段がある場合はそこを終点に固定する
+fn plan_tour(dangeon: &Dangeon, start: Coord, goals: Vec<Goal>) -> Vec<Goal> {
+    let end = goals.iter().find(|g| g.kind == GoalKind::Stair).cloned();
+    let rest: Vec<_> = goals
+        .into_iter()
+        .filter(|g| g.kind != GoalKind::Stair)
+        .collect();
+    if rest.is_empty() {
+        return end.into_iter().collect();
+    }
+    let mut points = vec![start];
+    points.extend(rest.iter().map(|g| g.cd));
+    points.extend(end.iter().map(|g| g.cd));
+    let maps: Vec<_> = points
+        .iter()
+        .map(|&cd| dangeon.make_dist_map(cd))
+        .collect();
+    let dist = |i: usize, j: usize| -> i32 {
+        maps[i]
+            .as_ref()
+            .and_then(|m| m.get(points[j]).cloned())
+            .unwrap_or(INF_DIST)
+    };
+    // 貪欲法
+    let mut path = vec![0];
+    let mut remain: Vec<_> = (1..rest.len() + 1).collect();
+    while !remain.is_empty() {
+        let cur = path[path.len() - 1];
+        let (i, _) = remain
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &p)| dist(cur, p))
+            .expect("remain is not empty");
+        path.push(remain.remove(i));
+    }
+    if end.is_some() {
+        path.push(rest.len() + 1);
+    }
+    // 2-opt 始点(と終点の階段)は動かさない
+    let last = if end.is_some() {
+        path.len() - 2
+    } else {
+        path.len() - 1
+    };
+    let cost = |path: &[usize]| -> i64 {
+        path.windows(2)
+            .fold(0, |acc, w| acc + i64::from(dist(w[0], w[1])))
+    };
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 1..last {
+            for j in i + 1..last + 1 {
+                let mut nxt = path.clone();
+                nxt[i..j + 1].reverse();
+                if cost(&nxt) < cost(&path) {
+                    path = nxt;
+                    improved = true;
+                }
+            }
+        }
+    }
+    let mut res: Vec<_> = path.iter().skip(1).filter_map(|&p| rest.get(p - 1).cloned()).collect();
+    res.extend(end);
+    res
+}
+
+// 隠し扉・隠し通路の推定部
+// rogueのレベル生成では9つの部屋スロットは全て通路で連結されるので、
+// 未発見のスロットに面した壁や、行き止まりの通路の先に隠し通路があると考える
+mod secret {
+    use super::*;
+
+    // 行き止まりの通路は隠し通路がない限りまず生成されない
+    const DEAD_END_PRIOR: f64 = 0.9;
+    // 事後確率がこれを下回ったらsearchをやめる
+    const STOP_POSTERIOR: f64 = 0.05;
+    const MAX_SEARCH_TURNS: u32 = 20;
+    // 部屋のないスロットでこれ以上通路が分岐していれば迷路とみなす
+    const MAZE_JUNCTION_MIN: usize = 4;
+    // 通路がスロットの幅か高さのこれだけの割合を横切っていれば、そこに部屋はない
+    const GONE_SPREAD_RATE: f64 = 0.6;
+    // 交差点が見えているだけのスロットに部屋がある確率
+    const JUNCTION_ROOM_RATE: f64 = 0.3;
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum SlotState {
+        Unknown,
+        Room,
+        // 深い階で生成される迷路部屋(壁がなく通路だけでできている)
+        Maze,
+        // 部屋が生成されず、通路の交差点だけがある
+        Gone,
+        // 通路の交差点が見えているが、部屋がないとは言い切れない
+        Junction,
+    }
+
+    impl SlotState {
+        // まだ見つかっていない部屋がある確率
+        fn room_rate(self) -> f64 {
+            match self {
+                SlotState::Unknown => 1.0,
+                SlotState::Junction => JUNCTION_ROOM_RATE,
+                _ => 0.0,
+            }
+        }
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct Candidate {
+        cd: Coord,
+        weight: f64,
+        find_rate: f64,
+        gain: ActionVal,
+    }
+
+    // 「高々1つの候補に隠し通路がある」という仮説の集まり
+    // priorはどれかの候補に隠し通路がある事前確率
+    struct HiddenGroup {
+        prior: f64,
+        cands: Vec<Candidate>,
+    }
+
+    impl HiddenGroup {
+        // 今までのsearchで見つかっていないことを条件にした各候補の事後確率
+        fn posterior(&self, dangeon: &Dangeon) -> Vec<(Candidate, f64)> {
+            let total_w = self.cands.iter().fold(0.0, |acc, c| acc + c.weight);
+            if total_w <= 0.0 {
+                return Vec::new();
+            }
+            let likelihood: Vec<_> = self.cands
+                .iter()
+                .map(|c| {
+                    let not_found = dangeon
+                        .get(c.cd)
+                        .map_or(1.0, |cell| cell.not_found_rate(c.find_rate));
+                    self.prior * c.weight / total_w * not_found
+                })
+                .collect();
+            let denom = likelihood.iter().sum::<f64>() + 1.0 - self.prior;
+            self.cands
+                .iter()
+                .cloned()
+                .zip(likelihood.into_iter().map(|l| l / denom))
+                .collect()
+        }
+    }
+
+    // 各マスに隠し通路がある事後確率
+    pub struct SecretMap {
+        posterior: SimpleMap<f64>,
+        find_rate: SimpleMap<f64>,
+        gain: SimpleMap<ActionVal>,
+    }
+
+    impl SecretMap {
+        pub fn new(dangeon: &Dangeon, player_cd: Coord) -> SecretMap {
+            let mut res = SecretMap {
+                posterior: SimpleMap::new(0.0),
+                find_rate: SimpleMap::new(0.0),
+                gain: SimpleMap::new(ActionVal::default()),
+            };
+            // 魔法の地図で隠し扉も隠し通路も描かれている
+            if dangeon.mapped {
+                return res;
+            }
+            let slots = slot_states(dangeon);
+            let mut groups = wall_groups(dangeon, &slots);
+            groups.extend(dead_end_groups(dangeon, &slots, player_cd));
+            for group in &groups {
+                for (cand, post) in group.posterior(dangeon) {
+                    // 複数の仮説に含まれるマスは独立とみなして合成する
+                    if let Some(p) = res.posterior.get_mut(cand.cd) {
+                        *p = 1.0 - (1.0 - *p) * (1.0 - post);
                     }
-                } else if i < 4 {
-                    if let Some(nxt_cell) = self.get(nxt_cd) {
-                        let cur_cell = or_empty_vec!(self.get(cd));
-                        if nxt_cell.surface != Surface::Wall || !cur_cell.surface.can_be_floor() {
-                            continue;
-                        }
-                        let move_dir = if i < 2 {
-                            [Direc::Left, Direc::Right]
-                        } else {
-                            [Direc::Up, Direc::Down]
-                        };
-                        let mut min_vis = (cd, cur_cell.hist.searched);
-                        for &wall_d in &move_dir {
-                            for wall_cd in cd.direc_iter(wall_d).expect("Error in find walls") {
-                                let wall_cell = or_empty_vec!(self.get(wall_cd));
-                                if !wall_cell.surface.can_be_floor() {
-                                    break;
-                                }
-                                if min_vis.1 > wall_cell.hist.searched {
-                                    min_vis = (wall_cd, wall_cell.hist.searched);
-                                }
-                            }
-                        }
-                        res.push((min_vis.0, d));
+                    if let Some(r) = res.find_rate.get_mut(cand.cd) {
+                        *r = r.max(cand.find_rate);
+                    }
+                    if let Some(g) = res.gain.get_mut(cand.cd) {
+                        *g = cmp::max(*g, cand.gain);
                     }
                 }
             }
+            res
+        }
+
+        // cdでsearchを続けた場合の評価値と、続けるべき回数
+        pub fn search_val(&self, cd: Coord) -> (ActionVal, u32) {
+            let around: Vec<_> = Direc::vars()
+                .filter_map(|d| {
+                    let ncd = cd + d.to_cd();
+                    let post = *self.posterior.get(ncd)?;
+                    if post <= 0.0 {
+                        return None;
+                    }
+                    Some((post, *self.find_rate.get(ncd)?, *self.gain.get(ncd)?))
+                })
+                .collect();
+            let turns = around
+                .iter()
+                .map(|&(post, rate, _)| search_turns(post, rate))
+                .max()
+                .unwrap_or(0);
+            let val = around
+                .iter()
+                .fold(ActionVal::default(), |acc, &(post, rate, gain)| {
+                    let find = post * (1.0 - (1.0 - rate).powi(turns as i32));
+                    acc + gain * ActionVal(find)
+                });
+            (val, turns)
+        }
+
+        #[cfg(test)]
+        pub fn posterior(&self, cd: Coord) -> f64 {
+            *self.posterior.get(cd).unwrap_or(&0.0)
         }
-        res
     }
 
-    pub fn search(&self, dist: &SimpleMap<i32>, player_cd: Coord) -> Option<(Coord, ActionVal)> {
-        let mut has_room = [false; 9];
-        self.iter().for_each(|(cell, cd)| {
+    // 見つからないまま事後確率がSTOP_POSTERIORを下回るまでのsearch回数
+    fn search_turns(post: f64, find_rate: f64) -> u32 {
+        if post <= STOP_POSTERIOR {
+            return 0;
+        }
+        if post >= 1.0 {
+            return MAX_SEARCH_TURNS;
+        }
+        // post * f^k / (post * f^k + 1 - post) < STOP_POSTERIOR をkについて解く
+        let odds = STOP_POSTERIOR * (1.0 - post) / ((1.0 - STOP_POSTERIOR) * post);
+        let k = (odds.ln() / (1.0 - find_rate).ln()).ceil();
+        (k as u32).clamp(1, MAX_SEARCH_TURNS)
+    }
+
+    pub fn slot_states(dangeon: &Dangeon) -> [SlotState; 9] {
+        let mut res = [SlotState::Unknown; 9];
+        let mut junctions = [0; 9];
+        // 隣のスロットから入ってきている通路(スロットごとのビット)
+        let mut entries = [0u16; 9];
+        // 通路のマスの範囲(左上, 右下)
+        let mut spans: [Option<(Coord, Coord)>; 9] = [None; 9];
+        for (cell, cd) in dangeon.iter() {
             let block = cd.block();
-            has_room[*block as usize] = cell.surface == Surface::Floor;
-        });
-        let calc_nonroom_area = |cd: Coord, dir: Direc| -> u8 {
-            let mut block = cd.block();
-            let mut cnt = 0;
-            while let Some(nxt_blk) = block.iterate(dir) {
-                if !has_room[*nxt_blk as usize] {
-                    cnt += 1;
+            let b = *block as usize;
+            match cell.surface {
+                Surface::Floor | Surface::Wall | Surface::Door => res[b] = SlotState::Room,
+                Surface::Road => {
+                    let mut branch = 0;
+                    for d in Direc::vars().take(4) {
+                        let ncd = cd + d.to_cd();
+                        if !dangeon.get(ncd).is_some_and(|c| c.surface.can_be_road()) {
+                            continue;
+                        }
+                        branch += 1;
+                        if ncd.block() != block {
+                            entries[b] |= 1 << *ncd.block();
+                        }
+                    }
+                    if branch >= 3 {
+                        junctions[b] += 1;
+                    }
+                    spans[b] = Some(match spans[b] {
+                        Some((l, r)) => (
+                            Coord::new(cmp::min(l.x, cd.x), cmp::min(l.y, cd.y)),
+                            Coord::new(cmp::max(r.x, cd.x), cmp::max(r.y, cd.y)),
+                        ),
+                        None => (cd, cd),
+                    });
                 }
-                block = nxt_blk;
+                _ => {}
             }
-            cnt
-        };
-
-        // calc dead end
-        let dead_end = self.find_dead_end(player_cd);
-        let mut dead_end_val = ActionVal::default();
-
-        let dead_end_cd = dead_end.iter().max_by_key(|cd_and_dir| {
-            let nr_areas = calc_nonroom_area(cd_and_dir.0, cd_and_dir.1);
-            let dis = *dist.get(cd_and_dir.0).unwrap_or(&INF_DIST);
-            let val = ActionVal::search(nr_areas).comp_dist(dis);
-            // 探索回数によるペナルティ
-            let pena = if let Some(cell) = self.get(cd_and_dir.0) {
-                cell.search_suc_rate()
-            } else {
-                1.0
-            };
-            let val = val.comp_suc_rate(pena);
-            dead_end_val = cmp::max(dead_end_val, val);
-            val
-        });
-
-        // calc suspicious wall
-        let mut wall_val = ActionVal::default();
-        let walls = self.find_walls(player_cd);
-        let wall_cd = walls.iter().max_by_key(|cd_and_dir| {
-            let nr_areas = calc_nonroom_area(cd_and_dir.0, cd_and_dir.1);
-            let dis = *dist.get(cd_and_dir.0).unwrap_or(&INF_DIST);
-            let val = ActionVal::search(nr_areas).comp_dist(dis);
-            // 探索回数によるペナルティ
-            let pena = if let Some(cell) = self.get(cd_and_dir.0) {
-                cell.search_suc_rate()
+        }
+        let (width, height) = geometry().block_size();
+        for b in 0..9 {
+            let cnt = junctions[b];
+            if res[b] != SlotState::Unknown || cnt == 0 {
+                continue;
+            }
+            // 通路は隣り合うスロットの部屋同士しか結ばないので、
+            // 部屋のないスロットで通路が分岐するのは交差点しかない
+            // ただ、部屋の横を通る通路や隣の交差点も分岐に見えるので、
+            // 隣の2つ以上のスロットからの通路が集まっているか、通路がスロットを
+            // 横切っているときだけ部屋の代わりの交差点とみなす
+            let spread = spans[b].is_some_and(|(l, r)| {
+                f64::from(r.x - l.x + 1) >= f64::from(width) * GONE_SPREAD_RATE
+                    || f64::from(r.y - l.y + 1) >= f64::from(height) * GONE_SPREAD_RATE
+            });
+            res[b] = if cnt >= MAZE_JUNCTION_MIN {
+                SlotState::Maze
+            } else if entries[b].count_ones() >= 2 || spread {
+                SlotState::Gone
             } else {
-                1.0
+                SlotState::Junction
             };
-            let val = val.comp_suc_rate(pena);
-            wall_val = cmp::max(wall_val, val);
-            val
-        });
+        }
+        res
+    }
 
-        if dead_end_val >= wall_val {
-            Some((dead_end_cd?.0, dead_end_val))
+    // cdのあるスロットから見てdir方向にある未発見のスロットの数
+    fn unknown_slots(slots: &[SlotState; 9], cd: Coord, dir: Direc) -> u8 {
+        let mut block = cd.block();
+        let mut cnt = if slots[*block as usize].room_rate() > 0.0 {
+            1
         } else {
-            Some((wall_cd?.0, wall_val))
+            0
+        };
+        while let Some(nxt_blk) = block.iterate(dir) {
+            if slots[*nxt_blk as usize].room_rate() > 0.0 {
+                cnt += 1;
+            }
+            block = nxt_blk;
         }
+        cnt
+    }
+
+    // blockにある部屋のface方向の壁と、その壁に既に扉があるかどうか
+    fn facing_walls(dangeon: &Dangeon, block: BlockVal, face: Direc) -> (Vec<Coord>, bool) {
+        let mut walls = Vec::new();
+        let mut has_exit = false;
+        for (cell, cd) in dangeon.iter().filter(|&(_, cd)| cd.block() == block) {
+            let inner_ok = dangeon
+                .get(cd - face.to_cd())
+                .is_some_and(|c| c.surface.can_be_floor());
+            if !inner_ok {
+                continue;
+            }
+            match cell.surface {
+                Surface::Door => has_exit = true,
+                Surface::Wall => {
+                    let outer_unknown = dangeon
+                        .get(cd + face.to_cd())
+                        .is_some_and(|c| c.surface == Surface::None);
+                    if outer_unknown {
+                        walls.push(cd);
+                    }
+                }
+                _ => {}
+            }
+        }
+        (walls, has_exit)
     }
 
-    pub fn find_stair(&self) -> Option<Coord> {
-        let cd = self.iter()
-            .find(|&cell_cd| cell_cd.0.surface == Surface::Stair)?;
-        Some(cd.1)
+    // 未発見のスロットそれぞれについて、隣接する既知の部屋の壁を候補にする
+    fn wall_groups(dangeon: &Dangeon, slots: &[SlotState; 9]) -> Vec<HiddenGroup> {
+        let mut res = Vec::new();
+        for b in 0..9 {
+            let room_rate = slots[b].room_rate();
+            if room_rate <= 0.0 {
+                continue;
+            }
+            let block = BlockVal(b as i8);
+            let neighbors: Vec<_> = Direc::vars()
+                .take(4)
+                .filter_map(|&d| Some((d, block.iterate(d)?)))
+                .collect();
+            let mut cands = Vec::new();
+            let mut hidden_cnt = 0;
+            for &(d, nb) in &neighbors {
+                if slots[*nb as usize] != SlotState::Room {
+                    continue;
+                }
+                let face = d.rotate_n(4);
+                let (walls, has_exit) = facing_walls(dangeon, nb, face);
+                if has_exit || walls.is_empty() {
+                    continue;
+                }
+                hidden_cnt += 1;
+                // 隣接する部屋ごとに同じ重みを持たせる
+                let weight = 1.0 / walls.len() as f64;
+                for cd in walls {
+                    cands.push(Candidate {
+                        cd,
+                        weight,
+                        find_rate: FIND_RATE_DOOR,
+                        gain: ActionVal::search(unknown_slots(slots, cd, face)),
+                    });
+                }
+            }
+            if !cands.is_empty() {
+                res.push(HiddenGroup {
+                    prior: room_rate * f64::from(hidden_cnt) / neighbors.len() as f64,
+                    cands,
+                });
+            }
+        }
+        res
     }
 
-    pub fn find_nearest_item(&self, dist: &SimpleMap<i32>) -> Option<(Coord, ActionVal)> {
-        let (cell, cd) = self.iter()
-            .filter(|cell_cd| cell_cd.0.obj.is_item())
-            .min_by_key(|cell_cd| *dist.get(cell_cd.1).unwrap_or(&0))?;
-        let act_val = if let FieldObject::Item(item) = cell.obj {
-            ActionVal::from_item(item)
-        } else {
-            ActionVal::default()
-        };
-        Some((cd, act_val))
+    // 行き止まりの通路の先の未知のマスを候補にする(直進方向を重視)
+    fn dead_end_groups(
+        dangeon: &Dangeon,
+        slots: &[SlotState; 9],
+        player_cd: Coord,
+    ) -> Vec<HiddenGroup> {
+        dangeon
+            .find_dead_end(player_cd)
+            .into_iter()
+            // 迷路の行き止まりは隠し通路と無関係
+            .filter(|&(cd, _)| slots[*cd.block() as usize] != SlotState::Maze)
+            .filter_map(|(cd, d)| {
+                let cands: Vec<_> = [d, d.rotate_n(2), d.rotate_n(6)]
+                    .iter()
+                    .filter_map(|&cand_d| {
+                        let ncd = cd + cand_d.to_cd();
+                        if dangeon.get(ncd)?.surface != Surface::None {
+                            return None;
+                        }
+                        Some(Candidate {
+                            cd: ncd,
+                            weight: if cand_d == d { 2.0 } else { 1.0 },
+                            find_rate: FIND_RATE_ROAD,
+                            gain: ActionVal::search(unknown_slots(slots, ncd, cand_d)),
+                        })
+                    })
+                    .collect();
+                if cands.is_empty() {
+                    None
+                } else {
+                    Some(HiddenGroup {
+                        prior: DEAD_END_PRIOR,
+                        cands,
+                    })
+                }
+            })
+            .collect()
     }
 }
 
@@ -645,8 +1309,9 @@ pub struct SimpleMap<T: Copy + Debug> {
 
 impl<T: Copy + Debug> SimpleMap<T> {
     pub fn new(init_val: T) -> SimpleMap<T> {
+        let geo = geometry();
         SimpleMap {
-            inner: vec![vec![init_val; COLUMNS]; LINES],
+            inner: vec![vec![init_val; geo.columns]; geo.map_lines()],
         }
     }
     pub fn iter(&self) -> CoordIter<SimpleMap<T>> {
@@ -699,7 +1364,7 @@ where
     fn next(&mut self) -> Option<(&'a T::Item, Coord)> {
         let before = self.cd;
         self.cd.x += 1;
-        if self.cd.x >= COLUMNS as _ {
+        if self.cd.x >= geometry().columns as _ {
             self.cd.x = 0;
             self.cd.y += 1;
         }
@@ -723,7 +1388,7 @@ where
     fn next(&mut self) -> Option<(&'a mut T::Item, Coord)> {
         let before = self.cd;
         self.cd.x += 1;
-        if self.cd.x >= COLUMNS as _ {
+        if self.cd.x >= geometry().columns as _ {
             self.cd.x = 0;
             self.cd.y += 1;
         }
@@ -769,7 +1434,11 @@ impl Coord {
         }
     }
     fn range_ok(&self) -> bool {
-        self.x >= 0 && self.y >= 0 && self.x < COLUMNS as _ && self.y < LINES as _
+        let geo = geometry();
+        self.x >= 0 && self.y >= 0 && self.x < geo.columns as _ && self.y < geo.map_lines() as _
+    }
+    pub fn dist_cheb(&self, other: &Coord) -> i32 {
+        cmp::max((self.x - other.x).abs(), (self.y - other.y).abs())
     }
     pub fn dist_euc(&self, other: &Coord) -> EucDist {
         let x = self.x - other.x;
@@ -783,18 +1452,11 @@ impl Coord {
     //  -   -   -
     //  6 | 7 | 8
     pub fn block(&self) -> BlockVal {
-        // TODO: remove hard coding
-        let row = match self.y {
-            val if val < 7 => 0,
-            val if val < 14 => 1,
-            _ => 2,
-        };
-        let col = match self.x {
-            val if val < 26 => 0,
-            val if val < 53 => 1,
-            _ => 2,
-        };
-        BlockVal(row * 3 + col)
+        let (width, height) = geometry().block_size();
+        // 画面の1行目はメッセージ欄なので1行ずらす
+        let row = cmp::min((self.y + 1) / height, 2);
+        let col = cmp::min(self.x / width, 2);
+        BlockVal((row * 3 + col) as i8)
     }
 }
 
@@ -802,16 +1464,16 @@ impl BlockVal {
     fn iterate(&self, dir: Direc) -> Option<Self> {
         match dir {
             Direc::Up => {
-                let res = *self + BlockVal(3);
-                if *res > 8 {
+                let res = *self - BlockVal(3);
+                if *res < 0 {
                     None
                 } else {
                     Some(res)
                 }
             }
             Direc::Down => {
-                let res = *self - BlockVal(3);
-                if *res < 0 {
+                let res = *self + BlockVal(3);
+                if *res > 8 {
                     None
                 } else {
                     Some(res)
@@ -819,18 +1481,17 @@ impl BlockVal {
             }
             Direc::Right => {
                 let res = *self + BlockVal(1);
-                if *res % 3 != **self % 3 {
+                if *res / 3 != **self / 3 {
                     None
                 } else {
                     Some(res)
                 }
             }
             Direc::Left => {
-                let res = *self - BlockVal(1);
-                if *res % 3 != **self % 3 {
+                if **self % 3 == 0 {
                     None
                 } else {
-                    Some(res)
+                    Some(*self - BlockVal(1))
                 }
             }
             _ => None,
@@ -919,10 +1580,10 @@ impl Rect {
         }
     }
     fn range_ok(&self, cd: Coord) -> bool {
-        self.l.x <= cd.x && cd.x <= self.r.y && self.l.y <= cd.y && cd.y <= self.r.y
+        self.l.x <= cd.x && cd.x <= self.r.x && self.l.y <= cd.y && cd.y <= self.r.y
     }
     fn check_pos(&self, cd: Coord) -> RectPos {
-        if self.l.x < cd.x && cd.x < self.r.y && self.l.y < cd.y && cd.y < self.r.y {
+        if self.l.x < cd.x && cd.x < self.r.x && self.l.y < cd.y && cd.y < self.r.y {
             RectPos::In
         } else if self.range_ok(cd) {
             RectPos::OnLine
@@ -994,7 +1655,7 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
-    use testutils::make_dangeon;
+    use testutils::{make_dangeon, str_to_buf};
     // complete map
     const MAP1: &str = "
                             ----------
@@ -1035,6 +1696,227 @@ mod test {
         println!("{:?}", d.explore_rate());
     }
 
+    const MAP2: &str = "
+ ------------
+ |..........|
+ |....@.....+######
+ |..........|
+ ------------
+";
+    #[test]
+    fn test_secret() {
+        let mut d = make_dangeon(MAP2);
+        let cur = d.player_cd().unwrap();
+        for x in 12..19 {
+            d.visit(Coord::new(x, 2));
+        }
+        let dead_end = Coord::new(18, 2);
+        let hidden = Coord::new(19, 2);
+        let before = secret::SecretMap::new(&d, cur).posterior(hidden);
+        assert!(before > 0.4);
+        let dist = d.make_dist_map(cur).unwrap();
+        let (search_cd, _, turns) = d.search(&dist, cur).unwrap();
+        assert!(search_cd.dist_euc(&dead_end) < EucDist(1.5));
+        assert!(turns > 0);
+        for _ in 0..turns {
+            d.searched(dead_end);
+        }
+        let after = secret::SecretMap::new(&d, cur).posterior(hidden);
+        assert!(after < before);
+        // 下の壁は未発見のスロットに面している
+        let wall = secret::SecretMap::new(&d, cur).posterior(Coord::new(5, 4));
+        assert!(wall > 0.0);
+    }
+
+    const MAP_GONE: &str = "
+ ------------
+ |..........|
+ |....@.....|
+ |..........|                 ################
+ ------------                      #
+                                   #
+                                   #
+";
+    #[test]
+    fn test_gone() {
+        let d = make_dangeon(MAP_GONE);
+        let cur = d.player_cd().unwrap();
+        let slots = secret::slot_states(&d);
+        assert_eq!(slots[0], secret::SlotState::Room);
+        // 通路がスロットを横切っている
+        assert_eq!(slots[1], secret::SlotState::Gone);
+        assert_eq!(slots[3], secret::SlotState::Unknown);
+        let secrets = secret::SecretMap::new(&d, cur);
+        // 交差点しかないスロットに向けてsearchしない
+        assert_eq!(secrets.posterior(Coord::new(12, 2)), 0.0);
+        assert!(secrets.posterior(Coord::new(5, 4)) > 0.0);
+    }
+
+    const MAP_JUNCTION: &str = "
+ ------------
+ |..........|
+ |....@.....|              ########
+ |..........|                 #
+ ------------                 #
+";
+    const MAP_JUNCTION_ROOM: &str = "
+ ------------                           -----------
+ |..........|                           |.........|
+ |....@.....|              ########     |.........|
+ |..........|                 #         |.........|
+ ------------                 #         -----------
+";
+    #[test]
+    fn test_junction() {
+        // 部屋と交差点が同じスロットにあれば部屋
+        let d = make_dangeon(MAP_JUNCTION_ROOM);
+        assert_eq!(secret::slot_states(&d)[1], secret::SlotState::Room);
+        // 交差点が1つ見えただけでは部屋がないとは言い切れない
+        let d = make_dangeon(MAP_JUNCTION);
+        let cur = d.player_cd().unwrap();
+        assert_eq!(secret::slot_states(&d)[1], secret::SlotState::Junction);
+        let wall = Coord::new(12, 2);
+        let junction = secret::SecretMap::new(&d, cur).posterior(wall);
+        // 通路が見えていなければ部屋がある確率はもっと高い
+        let d = make_dangeon(&MAP_JUNCTION.replace('#', " "));
+        let unknown = secret::SecretMap::new(&d, cur).posterior(wall);
+        assert!(junction > 0.0);
+        assert!(junction < unknown);
+    }
+
+    const MAP_MAZE: &str = "
+ #########
+ # # #   #
+ # ### ###
+ #   ##  #
+ ### ### #
+ @ #   # #
+";
+    #[test]
+    fn test_maze() {
+        let mut d = make_dangeon(MAP_MAZE);
+        let cur = d.player_cd().unwrap();
+        let roads: Vec<_> = d.iter()
+            .filter(|&(c, _)| c.surface == Surface::Road)
+            .map(|(_, cd)| cd)
+            .collect();
+        for cd in roads {
+            d.visit(cd);
+        }
+        assert_eq!(secret::slot_states(&d)[0], secret::SlotState::Maze);
+        // 迷路の行き止まりではsearchしない
+        let secrets = secret::SecretMap::new(&d, cur);
+        assert_eq!(secrets.posterior(Coord::new(6, 2)), 0.0);
+        // 通路の角は斜めに曲がれないが、広い場所なら斜めに進める
+        assert!(!d.can_move(Coord::new(2, 4), Direc::RightDown));
+        assert!(d.can_move(Coord::new(5, 3), Direc::RightDown));
+        let dist = d.make_dist_map(cur).unwrap();
+        assert_eq!(*dist.get(Coord::new(9, 5)).unwrap(), 18);
+    }
+
+    #[test]
+    fn test_item_memory() {
+        let mut d = make_dangeon(&MAP1);
+        let potion = Coord::new(29, 17);
+        assert_eq!(d.get(potion).unwrap().memory.map(|m| m.item), Some(Item::Potion));
+        // 敵が上に乗っても覚えている
+        let hidden = MAP1.replace("|.!....", "|.K....");
+        d.merge(&str_to_buf(&hidden), 1);
+        assert_eq!(d.get(potion).unwrap().memory.map(|m| m.item), Some(Item::Potion));
+        let dist = d.make_dist_map(d.player_cd().unwrap()).unwrap();
+        assert_eq!(d.find_nearest_item(&dist).unwrap().0, potion);
+        // 空の床が見えたら忘れる
+        let empty = MAP1.replace("|.!....", "|......");
+        d.merge(&str_to_buf(&empty), 2);
+        assert_eq!(d.get(potion).unwrap().memory.map(|m| m.item), None);
+    }
+
+    #[test]
+    fn test_hallucination() {
+        let mut d = make_dangeon(MAP1);
+        let potion = Coord::new(29, 17);
+        d.set_hallucination(true);
+        // 幻覚中はアイテムの種類も階段の文字も信用しない
+        for fake in &["|.%....", "|.]...."] {
+            d.merge(&str_to_buf(&MAP1.replace("|.!....", fake)), 1);
+            let cell = d.get(potion).unwrap();
+            assert_eq!(cell.memory.map(|m| m.item), Some(Item::Potion));
+            assert_ne!(cell.surface, Surface::Stair);
+        }
+        d.set_hallucination(false);
+        d.merge(&str_to_buf(&MAP1.replace("|.!....", "|.]....")), 2);
+        assert_ne!(d.get(potion).unwrap().memory.map(|m| m.item), Some(Item::Potion));
+    }
+
+    const MAP3: &str = "
+          ------------
+   #######+....@.....+#########
+          |..........|
+          ------------
+";
+    #[test]
+    fn test_route() {
+        let d = make_dangeon(MAP3);
+        let cur = d.player_cd().unwrap();
+        let dist = d.make_dist_map(cur).unwrap();
+        let mut route = RoutePlan::default();
+        route.update(&d, cur, &dist);
+        // 近い左側を回ってから右側へ行く
+        let first = route.next_goal().unwrap();
+        assert!(first.cd.x < cur.x);
+        assert!(route.goals.last().unwrap().cd.x > cur.x);
+        let plan = route.goals.clone();
+        route.update(&d, cur, &dist);
+        assert_eq!(plan, route.goals);
+        let (explore_cd, _) = d.explore(&dist, &route).unwrap();
+        assert_eq!(explore_cd, first.cd);
+    }
+
+    const MAP_MAPPED: &str = "
+ ------------
+ |..........|
+ |....@.....+########       ------
+ |..........|       ########+    |
+ ------------               |  % |
+                            ------
+";
+    #[test]
+    fn test_detection() {
+        let mut d = make_dangeon(MAP2);
+        let cur = d.player_cd().unwrap();
+        d.visit(cur);
+        d.merge_detected(&str_to_buf(MAP_MAPPED), 1, Detection::Map);
+        // 描かれていない床も埋まり、階段まで直行できる
+        let floor = d.get(Coord::new(30, 3)).unwrap();
+        assert_eq!(floor.surface, Surface::Floor);
+        assert!(floor.is_detected());
+        let dist = d.make_dist_map(cur).unwrap();
+        let stair = d.find_stair().unwrap();
+        assert!(*dist.get(stair).unwrap() < INF_DIST);
+        let mut route = RoutePlan::default();
+        route.update(&d, cur, &dist);
+        assert_eq!(route.goals, vec![Goal { cd: stair, kind: GoalKind::Stair }]);
+        assert_eq!(secret::SecretMap::new(&d, cur).posterior(Coord::new(5, 4)), 0.0);
+        // 探知の結果は次の画面で消えても覚えている
+        let mut window = vec![vec![b' '; geometry().columns]; geometry().map_lines()];
+        window[3][30] = b'$';
+        d.merge_detected(&window, 2, Detection::Magic);
+        d.merge(&str_to_buf(MAP_MAPPED), 3);
+        let item = d.get(Coord::new(30, 3)).unwrap().memory.map(|m| m.item);
+        assert_eq!(item, Some(Item::Magic));
+        let dist = d.make_dist_map(cur).unwrap();
+        assert_eq!(d.find_nearest_item(&dist).unwrap().0, Coord::new(30, 3));
+    }
+
+    #[test]
+    fn test_block() {
+        assert_eq!(*Coord::new(25, 6).block(), 0);
+        assert_eq!(*Coord::new(26, 7).block(), 4);
+        assert_eq!(*Coord::new(52, 14).block(), 5);
+        assert_eq!(*Coord::new(79, 21).block(), 8);
+        assert_eq!(Geometry::new(100, 30).block_size(), (33, 10));
+    }
+
     #[test]
     fn test_rect_iter() {
         let d = make_dangeon(&MAP1);
@@ -1053,10 +1935,37 @@ mod test {
             } else {
                 match rect.check_pos(cd) {
                     RectPos::In => assert_eq!(cell.surface, Surface::Floor),
-                    RectPos::OnLine => assert_eq!(cell.surface, Surface::Wall),
+                    RectPos::OnLine => {
+                        let doors = [Coord::new(18, 8), Coord::new(25, 9), Coord::new(17, 13)];
+                        if doors.contains(&cd) {
+                            assert_eq!(cell.surface, Surface::Door);
+                        } else {
+                            assert_eq!(cell.surface, Surface::Wall);
+                        }
+                    }
                     _ => {}
                 }
             }
         }
+        // 横長の部屋の右側もRectの内側
+        assert_eq!(rect.check_pos(Coord::new(20, 10)), RectPos::In);
+        assert_eq!(rect.check_pos(Coord::new(25, 10)), RectPos::OnLine);
+        assert_eq!(rect.check_pos(Coord::new(26, 10)), RectPos::Out);
+    }
+
+    #[test]
+    fn test_block_iter() {
+        // 0 1 2
+        // 3 4 5
+        // 6 7 8
+        let center = BlockVal(4);
+        assert_eq!(center.iterate(Direc::Up), Some(BlockVal(1)));
+        assert_eq!(center.iterate(Direc::Down), Some(BlockVal(7)));
+        assert_eq!(center.iterate(Direc::Left), Some(BlockVal(3)));
+        assert_eq!(center.iterate(Direc::Right), Some(BlockVal(5)));
+        assert_eq!(BlockVal(1).iterate(Direc::Up), None);
+        assert_eq!(BlockVal(7).iterate(Direc::Down), None);
+        assert_eq!(BlockVal(3).iterate(Direc::Left), None);
+        assert_eq!(BlockVal(5).iterate(Direc::Right), None);
     }
 }
@@ -134,14 +134,45 @@ pub enum Action {
     Die,
     Space,
     Enter,
+    Escape,
     None,
 }
 
 default_none!(Action);
 
+impl Action {
+    // ゲーム内の時間が進む行動(--More--を送ったりプロンプトを閉じたりするのは含まない)
+    pub fn takes_turn(self) -> bool {
+        let no_turn = [
+            Action::Space,
+            Action::Enter,
+            Action::Escape,
+            Action::None,
+            Action::SaveGame,
+            Action::Quit,
+            Action::Die,
+        ];
+        !no_turn.contains(&self)
+    }
+    // コマンドと一緒に送るアイテム("Which object"への答え)
+    pub fn item_id(self) -> Option<u8> {
+        match self {
+            Action::QuaffPotion(b)
+            | Action::ReadScroll(b)
+            | Action::EatFood(b)
+            | Action::WieldWeapon(b)
+            | Action::WearArmor(b)
+            | Action::PutOnRing(b)
+            | Action::DropObject(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
 lazy_static! {
     static ref ENTER: u8 = AsciiChar::CarriageReturn.as_byte();
     static ref SPACE: u8 = AsciiChar::Space.as_byte();
+    static ref ESCAPE: u8 = AsciiChar::ESC.as_byte();
 }
 
 impl Into<Vec<u8>> for Action {
@@ -168,11 +199,41 @@ impl Into<Vec<u8>> for Action {
             Action::Die => vec![*ENTER, *ENTER],
             Action::Space => vec![*SPACE],
             Action::Enter => vec![*ENTER],
+            Action::Escape => vec![*ESCAPE],
             Action::None => vec![],
         }
     }
 }
 
+// 満腹度(残りターン数)の目安 rogueのHUNGERTIME, STOMACHSIZE
+pub const FOOD_RATION: i32 = 1300;
+pub const STOMACH_SIZE: i32 = 2000;
+// これを下回るとHungry, Weakと表示される
+const FOOD_HUNGRY: i32 = 300;
+const FOOD_WEAK: i32 = 150;
+
+// はめている指輪1つが1ターンに余計に減らす満腹度の期待値 rogueのring_eat
+// 負の値の指輪は1/nの確率で1減らす slow digestionは逆に1/2の確率で1増やす
+// 何の指輪か分からないものは減らさないとみなす
+pub fn ring_eat(name: &str) -> f64 {
+    match name {
+        "protection" | "add strength" | "sustain strength" | "stealth" | "maintain armor" => 1.0,
+        "searching" | "dexterity" | "increase damage" => 1.0 / 3.0,
+        "see invisible" => 1.0 / 5.0,
+        "regeneration" => 2.0,
+        "slow digestion" => -0.5,
+        _ => 0.0,
+    }
+}
+
+// レベルL+1になるのに必要な経験値がEXP_LEVELS[L-1] rogueのe_levels
+const EXP_LEVELS: [i32; 20] = [
+    10, 20, 40, 80, 160, 320, 640, 1300, 2600, 5200, 13000, 26000, 50000, 100000, 200000, 400000,
+    800000, 2000000, 4000000, 8000000,
+];
+// レベルが1上がるごとに最大HPがroll(1, 10)増える
+pub const HP_PER_LEVEL: Dice = Dice::new(1, 10);
+
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct PlayerStatus {
     pub stage_level: i8,
@@ -226,9 +287,67 @@ impl PlayerStatus {
         *self = new_stat;
         res
     }
+    // 次のレベルまでに必要な経験値 最大レベルならNone
+    pub fn exp_to_next(&self) -> Option<i32> {
+        let next = EXP_LEVELS.get(cmp::max(self.exp_level - 1, 0) as usize)?;
+        Some(cmp::max(next - self.exp, 0))
+    }
+    // exp得たときに上がるレベル数 途中までの分は次のレベルまでの割合で数える
+    pub fn level_progress(&self, exp: i32) -> f64 {
+        let (mut cur, mut left, mut res) = (self.exp, exp, 0.0);
+        for lv in cmp::max(self.exp_level, 1)..EXP_LEVELS.len() as i32 + 1 {
+            if left <= 0 {
+                break;
+            }
+            let prev = if lv == 1 { 0 } else { EXP_LEVELS[lv as usize - 2] };
+            let next = EXP_LEVELS[lv as usize - 1];
+            let gain = cmp::min(left, cmp::max(next - cur, 0));
+            res += f64::from(gain) / f64::from(next - prev);
+            left -= gain;
+            cur = next;
+        }
+        res
+    }
+    // k回レベルが上がった後の最大HPの期待値
+    pub fn max_hp_after(&self, k: f64) -> f64 {
+        f64::from(self.max_hp) + k * *HP_PER_LEVEL.expect_val()
+    }
+    pub fn enough_hp(&self) -> i32 {
+        cmp::max(8, self.max_hp / 2 + 1)
+    }
     pub fn have_enough_hp(&self) -> bool {
-        let threshold = cmp::max(8, self.max_hp / 2 + 1);
-        self.cur_hp >= threshold
+        self.cur_hp >= self.enough_hp()
+    }
+    // rogueのdoctor()による1ターンあたりの回復量の期待値
+    // 回復の指輪をはめているとHPが毎ターン変わるので、レベルによる回復は起きなくなる
+    pub fn regen_rate(&self, regen_rings: i32) -> f64 {
+        let lv = self.exp_level;
+        if regen_rings > 0 {
+            f64::from(regen_rings)
+        } else if lv < 8 {
+            1.0 / f64::from(21 - 2 * lv)
+        } else {
+            // 3ターンごとにrnd(lv - 7) + 1
+            f64::from(lv - 6) / 2.0 / 3.0
+        }
+    }
+    // hpまで回復するのにかかるターン数
+    pub fn turns_to_hp(&self, hp: i32, regen_rings: i32) -> usize {
+        let need = cmp::min(hp, self.max_hp) - self.cur_hp;
+        if need <= 0 {
+            return 0;
+        }
+        (f64::from(need) / self.regen_rate(regen_rings)).ceil() as usize
+    }
+    // 最後に食べた時の満腹度とそれからのターン数から、今の満腹度を見積もる
+    // rings_eatははめている指輪のring_eatの和
+    pub fn food_left(&self, ate: i32, elapsed: usize, rings_eat: f64) -> i32 {
+        let left = ate - (elapsed as f64 * (1.0 + rings_eat)).round() as i32;
+        match self.hungry_level {
+            0 => cmp::max(left, FOOD_HUNGRY),
+            1 => left.clamp(FOOD_WEAK, FOOD_HUNGRY - 1),
+            _ => left.clamp(0, FOOD_WEAK - 1),
+        }
     }
 }
 
@@ -252,22 +371,36 @@ pub enum GameMsg {
     CallIt,
     NotValid,
     NoStair,
+    Hallucinate(bool), // 幻覚の開始と終了
+    Detected(Detection),
+    Mimic, // アイテムだと思っていたものがXerocだった
+    Wielded,
+    Cursed, // 呪われた武器や鎧は外せない
     None,
 }
 
 default_none!(GameMsg);
 
+// 魔法の地図や探知の巻物・薬の効果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection {
+    Map,   // 部屋と通路と階段
+    Magic, // 魔法のかかったアイテム
+    Food,  // 食料
+    Monster, // 敵
+}
+
 impl GameMsg {
     pub fn near_enemy(&self) -> bool {
         use GameMsg::*;
         match *self {
-            NotInjured(_) | Injured(_) | Scored(_) | Defeated(_) | Missed(_) => true,
+            NotInjured(_) | Injured(_) | Scored(_) | Defeated(_) | Missed(_) | Mimic => true,
             _ => false,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Enemy {
     Aquator,
     Bat,
@@ -393,15 +526,112 @@ impl Enemy {
     pub fn has_attr(self, attr: EnemyAttr) -> bool {
         self.status().attr.contains(attr)
     }
+    pub fn speed(self) -> i32 {
+        self.status().speed
+    }
+    pub fn name(self) -> &'static str {
+        self.status().name
+    }
+    pub fn all() -> Vec<Enemy> {
+        (b'A'..b'Z' + 1).map(Enemy::from).collect()
+    }
+    pub fn movement(self) -> Movement {
+        if self.speed() == 0 {
+            Movement::Stationary
+        } else if self.has_attr(EnemyAttr::RANDOM) {
+            Movement::Random
+        } else if self.has_attr(EnemyAttr::GREEDY) {
+            Movement::Greedy
+        } else {
+            Movement::Chase
+        }
+    }
+}
+
+// rogue 5.4.4 の出現テーブル 深くなるほど右側の敵が出てくる
+const LVL_MONS: &[u8; 26] = b"KEBSHIROZLCQANYFTWPXUMVGJD";
+// さまよう敵としては出てこないもの(wand_monsで0になっている)
+const NOT_WANDER: &[u8; 6] = b"ILNFXD";
+
+// その階で新しく出てくる敵の確率
+// d = level + rnd(10) - 6 で、出てこない敵に当たったら振り直し
+pub fn appear_prob(level: i32, wander: bool) -> Vec<(Enemy, f64)> {
+    let mut prob = [0.0; 26];
+    for r in 0..10 {
+        let d = level + r - 6;
+        let (lo, hi) = if d < 0 {
+            (0, 5)
+        } else if d > 25 {
+            (21, 26)
+        } else {
+            (d as usize, d as usize + 1)
+        };
+        let w = 0.1 / (hi - lo) as f64;
+        for p in &mut prob[lo..hi] {
+            *p += w;
+        }
+    }
+    if wander {
+        for (p, c) in prob.iter_mut().zip(LVL_MONS.iter()) {
+            if NOT_WANDER.contains(c) {
+                *p = 0.0;
+            }
+        }
+    }
+    let sum: f64 = prob.iter().sum();
+    LVL_MONS
+        .iter()
+        .zip(prob.iter())
+        .filter(|&(_, &p)| p > 0.0)
+        .map(|(&c, &p)| (Enemy::from(c), p / sum))
+        .collect()
+}
+
+// 敵1体に対してアイテムはだいたい2個ある
+const MONSTER_PER_ITEM: f64 = 0.5;
+
+// 見えているアイテムが実はXerocである確率
+pub fn mimic_prob(level: i32) -> f64 {
+    appear_prob(level, false)
+        .into_iter()
+        .find(|&(e, _)| e == Enemy::Xeroc)
+        .map_or(0.0, |(_, p)| p * MONSTER_PER_ITEM)
+}
+
+// 候補のうちその階で一番出てきやすい敵
+pub fn likely_enemy(cands: &[Enemy], level: i32) -> Enemy {
+    appear_prob(level, false)
+        .into_iter()
+        .filter(|&(e, _)| cands.contains(&e))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map_or_else(|| cands.first().cloned().unwrap_or_default(), |(e, _)| e)
+}
+
+// 起きている敵がプレイヤーが見えているのにその場に留まる確率
+const STAY_RATE_AWAKE: f64 = 0.2;
+
+// 起きている敵の動き方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Movement {
+    Chase,      // まっすぐプレイヤーに近づく
+    Random,     // 半分の確率ででたらめに飛ぶ(Bat)
+    Stationary, // その場から動かない(Venus Flytrap)
+    Greedy,     // 部屋の金貨を先に取りに行く(Orc)
 }
 
 #[derive(Clone, Debug)]
 pub struct EnemyHist {
     pub cd: Coord,
-    pub hp_ex: DamageVal,
-    pub running: bool,
+    pub hp_ex: DamageVal, // hpの期待値
+    pub hp: HpDist,
+    pub dealt: f64,    // 与えたダメージの期待値(回復する敵用)
+    pub hp_turn: usize, // 最後に回復を計算したターン
+    pub running: bool, // 動いたり攻撃してきたりして、起きていると分かっている
+    pub awake: f64,    // runningでないときの起きている確率
     pub typ: Enemy,
     pub visible: bool, // このフィールドは探索時は無視
+    pub detected: bool, // 探知で位置を知っただけで、まだ見ていない
+    pub last_seen: usize, // 最後に見えたターン
 }
 
 impl EnemyHist {
@@ -409,9 +639,15 @@ impl EnemyHist {
         EnemyHist {
             cd: cd,
             hp_ex: typ.hp().expect_val(),
+            hp: EnemyHist::hp_prior(typ),
+            dealt: 0.0,
+            hp_turn: 0,
             running: false,
+            awake: 0.0,
             typ: typ,
             visible: true,
+            detected: false,
+            last_seen: 0,
         }
     }
     // just for test
@@ -419,14 +655,79 @@ impl EnemyHist {
         EnemyHist {
             cd: Coord::default(),
             hp_ex: typ.hp().expect_val(),
+            hp: EnemyHist::hp_prior(typ),
+            dealt: 0.0,
+            hp_turn: 0,
             running: false,
+            awake: 0.0,
             typ: typ,
             visible: true,
+            detected: false,
+            last_seen: 0,
+        }
+    }
+    pub fn awake_prob(&self) -> f64 {
+        if self.running {
+            1.0
+        } else {
+            self.awake
         }
     }
+    pub fn wake(&mut self) {
+        self.running = true;
+        self.awake = 1.0;
+    }
+    // MEANな敵は部屋に入られたり隣に来られたりすると2/3の確率で起きる
+    pub fn disturbed(&mut self) {
+        if self.typ.has_attr(EnemyAttr::MEAN) {
+            self.awake = 1.0 - (1.0 - self.awake) / 3.0;
+        }
+    }
+    // 起きていればプレイヤーに近づくはずなのにその場から動かなかった
+    pub fn stayed(&mut self) {
+        let awake = self.awake * STAY_RATE_AWAKE;
+        self.awake = awake / (awake + 1.0 - self.awake);
+    }
+    // 起きていればこのターンに動いたはずか 動かない敵や、隣で攻撃してくる敵は留まる
+    // rogue 5.4.4では最初からISSLOWの敵はいない(slow monsterの杖を当てたときだけ遅くなる)
+    pub fn would_move(&self, player: Coord) -> bool {
+        self.typ.speed() > 0 && self.cd.dist_cheb(&player) > 1
+    }
     pub fn is_live(&self) -> bool {
-        let threshold = -5.0;
-        *self.hp_ex > threshold
+        self.hp.death_prob() < 0.5
+    }
+    fn hp_prior(typ: Enemy) -> HpDist {
+        HpDist::from_pmf(typ.hp().pmf())
+    }
+    // 種類が分かり直したら、与えたダメージはそのままにHPの分布を作り直す
+    pub fn retype(&mut self, typ: Enemy) {
+        let dealt = DamagePmf::constant(self.dealt.round() as i32);
+        self.typ = typ;
+        self.hp = EnemyHist::hp_prior(typ).damaged(&dealt).alive();
+        self.hp_ex = self.hp.expect_val();
+    }
+    pub fn hit(&mut self, dam: &DamagePmf) {
+        self.hp = self.hp.damaged(dam);
+        self.hp_ex = self.hp.expect_val();
+        self.dealt += *dam.expect_val();
+    }
+    // 倒れていないので、HPは1以上
+    pub fn seen_alive(&mut self) {
+        self.hp = self.hp.alive();
+        self.hp_ex = self.hp.expect_val();
+    }
+    // REGENERATEの敵は1ターンに1ずつ回復する
+    pub fn regenerate(&mut self, turn: usize) {
+        let elapsed = turn.saturating_sub(self.hp_turn);
+        self.hp_turn = turn;
+        if !self.typ.has_attr(EnemyAttr::REGENERATE) || self.dealt < 1.0 {
+            return;
+        }
+        let n = cmp::min(elapsed, self.dealt as usize);
+        let cap = (self.typ.status().level * 8) as usize;
+        self.hp = self.hp.healed(n, cap);
+        self.hp_ex = self.hp.expect_val();
+        self.dealt -= n as f64;
     }
 }
 
@@ -468,222 +769,276 @@ macro_rules! enem_attr {
 
 #[derive(Debug)]
 struct EnemyStatus {
+    name: &'static str, // メッセージ中の名前
     treasure: i32,     // gold
     attr: EnemyAttr, /* MEANとか ゲーム内でflag使わないでif文処理してるやつもこれで */
     exp: i32,        // 得られる経験値
     level: i32,      // レベル (多分hit率およびhp)
     defence: i32,    // アーマー(これも多分hit率だけ)
+    speed: i32,      // 1ターンに動けるマス数(飛ぶ敵は離れていると2回動く、0なら動かない)
     attack: Vec<Dice>, // 攻撃
 }
 
 lazy_static!{
     static ref ENEMIES: [EnemyStatus; 26] =[
         EnemyStatus { // Aquator
+            name: "aquator",
             treasure: 0,
             attr: enem_attr!(MEAN, RUSTS_ARMOR),
             exp: 20,
             level: 5,
             defence: 2,
+            speed: 1,
             attack: vec![Dice::new(0, 0)],
         },
         EnemyStatus { // Bat
+            name: "bat",
             treasure: 0,
             attr: enem_attr!(FLYING, RANDOM),
             exp: 1,
             level: 1,
             defence: 3,
+            speed: 2,
             attack: vec![Dice::new(1, 2)],
         },
         EnemyStatus { // Centaur
+            name: "centaur",
             treasure: 15,
             attr: enem_attr!(),
             exp: 17,
             level: 4,
             defence: 4,
+            speed: 1,
             attack: vec![Dice::new(1, 2), Dice::new(1, 5), Dice::new(1, 5)],
         },
         EnemyStatus { // Dragon
+            name: "dragon",
             treasure: 100,
             attr: enem_attr!(MEAN),
             exp: 5000,
             level: 10,
             defence: 3,
+            speed: 1,
             attack: vec![Dice::new(1, 8), Dice::new(1, 8), Dice::new(3, 10)],
         },
         EnemyStatus { // Emu
+            name: "emu",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 2,
             level: 1,
             defence: 7,
+            speed: 1,
             attack: vec![Dice::new(1, 2)],
         },
         EnemyStatus { // Venus Flytrap
+            name: "venus flytrap",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 80,
             level: 8,
             defence: 3,
+            speed: 0,
             attack: vec![], // special
         },
         EnemyStatus { // Griffin
+            name: "griffin",
             treasure: 20,
             attr: enem_attr!(FLYING, MEAN, REGENERATE),
             exp: 2000,
             level: 13,
             defence: 2,
+            speed: 2,
             attack: vec![Dice::new(4, 3), Dice::new(3, 5)],
         },
         EnemyStatus { // Hobgoblin
+            name: "hobgoblin",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 3,
             level: 1,
             defence: 5,
+            speed: 1,
             attack: vec![Dice::new(1, 8)],
         },
         EnemyStatus { // Icemonster
+            name: "ice monster",
             treasure: 0,
             attr: enem_attr!(FREEZES),
             exp: 5,
             level: 1,
             defence: 9,
+            speed: 1,
             attack: vec![Dice::new(0, 0)],
         },
         EnemyStatus { // Jabberwock
+            name: "jabberwock",
             treasure: 70,
             attr: enem_attr!(),
             exp: 3000,
             level: 15,
             defence: 6,
+            speed: 1,
             attack: vec![Dice::new(2, 12), Dice::new(2, 4)],
         },
         EnemyStatus { // Kestrel
+            name: "kestrel",
             treasure: 0,
             attr: enem_attr!(),
             exp: 1,
             level: 1,
             defence: 7,
+            speed: 2,
             attack: vec![Dice::new(1, 4)],
         },
         EnemyStatus { // Leperachaun
+            name: "leprechaun",
             treasure: 0,
             attr: enem_attr!(STEAL_GOLD),
             exp: 10,
             level: 3,
             defence: 8,
+            speed: 1,
             attack: vec![Dice::new(1, 1)],
         },
         EnemyStatus { // Medusa
+            name: "medusa",
             treasure: 40,
             attr: enem_attr!(MEAN),
             exp: 200,
             level: 8,
             defence: 2,
+            speed: 1,
             attack: vec![Dice::new(3, 4), Dice::new(3, 4), Dice::new(2, 5)],
         },
         EnemyStatus { // Nymph
+            name: "nymph",
             treasure: 100,
             attr: enem_attr!(),
             exp: 37,
             level: 3,
             defence: 9,
+            speed: 1,
             attack: vec![Dice::new(0, 0)],
         },
         EnemyStatus { // Orc
+            name: "orc",
             treasure: 15,
             attr: enem_attr!(GREEDY),
             exp: 5,
             level: 1,
             defence: 6,
+            speed: 1,
             attack: vec![Dice::new(1, 8)],
         },
         EnemyStatus { // Phantom
+            name: "phantom",
             treasure: 0,
             attr: enem_attr!(INVISIBLE),
             exp: 120,
             level: 8,
             defence: 3,
+            speed: 1,
             attack: vec![Dice::new(4, 4)],
         },
         EnemyStatus { // Quagga
+            name: "quagga",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 15,
             level: 3,
             defence: 3,
+            speed: 1,
             attack: vec![Dice::new(1, 5), Dice::new(1, 5)],
         },
         EnemyStatus { // Rattlesnake
+            name: "rattlesnake",
             treasure: 0,
             attr: enem_attr!(REDUCE_STR, MEAN),
             exp: 9,
             level: 2,
             defence: 3,
+            speed: 1,
             attack: vec![Dice::new(1, 6)],
         },
         EnemyStatus { // Snake
+            name: "snake",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 2,
             level: 1,
             defence: 5,
+            speed: 1,
             attack: vec![Dice::new(1, 3)],
         },
         EnemyStatus { // Troll
+            name: "troll",
             treasure: 50,
             attr: enem_attr!(MEAN, REGENERATE),
             exp: 120,
             level: 6,
             defence: 4,
+            speed: 1,
             attack: vec![Dice::new(1, 8), Dice::new(1, 8), Dice::new(2, 6)],
         },
         EnemyStatus { // Urvile (Black Unicorn)
+            name: "black unicorn",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 190,
             level: 7,
             defence: -2,
+            speed: 1,
             attack: vec![Dice::new(1, 9), Dice::new(1, 9), Dice::new(2, 9)],
         },
         EnemyStatus { // Vampire
+            name: "vampire",
             treasure: 20,
             attr: enem_attr!(MEAN, REGENERATE),
             exp: 350,
             level: 8,
             defence: 1,
+            speed: 1,
             attack: vec![Dice::new(1, 19)],
         },
         EnemyStatus { // Wraith
+            name: "wraith",
             treasure: 0,
             attr: enem_attr!(),
             exp: 55,
             level: 5,
             defence: 4,
+            speed: 1,
             attack: vec![Dice::new(1, 6)],
         },
         EnemyStatus { // Xeroc
+            name: "xeroc",
             treasure: 30,
             attr: enem_attr!(),
             exp: 100,
             level: 7,
             defence: 7,
+            speed: 1,
             attack: vec![Dice::new(4, 4)],
         },
         EnemyStatus { // Yeti
+            name: "yeti",
             treasure: 30,
             attr: enem_attr!(),
             exp: 50,
             level: 4,
             defence: 6,
+            speed: 1,
             attack: vec![Dice::new(1, 6), Dice::new(1, 6)],
         },
         EnemyStatus { // Zombie
+            name: "zombie",
             treasure: 0,
             attr: enem_attr!(MEAN),
             exp: 6,
             level: 2,
             defence: 8,
+            speed: 1,
             attack: vec![Dice::new(1, 8)],
         }
     ];
@@ -700,6 +1055,7 @@ pub enum Item {
     Gold,
     Ring,
     Amulet,
+    Magic, // 魔法探知で見えた、種類の分からないアイテム
     None,
 }
 
@@ -729,6 +1085,7 @@ pub struct ItemPack {
     pub num: u32,
     pub typ: Item,
     pub val: Option<i32>, // Armorの固有値など
+    pub plus: Option<(i32, i32)>, // 武器の(命中, ダメージ)補正
 }
 
 impl ItemPack {
@@ -739,11 +1096,12 @@ impl ItemPack {
             num: num,
             typ: typ,
             val: None,
+            plus: None,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Weapon {
     Mace,
     LongSword,
@@ -814,6 +1172,13 @@ impl Weapon {
     pub fn throw(self) -> Dice {
         self.status().throw
     }
+    // これを持っていないと飛ばせない武器
+    pub fn launcher(self) -> Option<Weapon> {
+        match self {
+            Weapon::Arrow => Some(Weapon::Bow),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -831,6 +1196,21 @@ pub enum Armor {
 
 default_none!(Armor);
 
+impl Armor {
+    // rogueのa_class 表示されるArmは10 - (これ - 補正値)
+    pub fn class(self) -> i32 {
+        match self {
+            Armor::Leather => 8,
+            Armor::Ring | Armor::Studded => 7,
+            Armor::Scale => 6,
+            Armor::Chain => 5,
+            Armor::Splint | Armor::Banded => 4,
+            Armor::Plate => 3,
+            Armor::None => 10,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Food {
     Ration,
@@ -916,11 +1296,3 @@ impl From<u8> for FieldObject {
     }
 }
 
-impl FieldObject {
-    pub fn is_item(&self) -> bool {
-        match *self {
-            FieldObject::Item(_) => true,
-            _ => false,
-        }
-    }
-}
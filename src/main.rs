@@ -33,9 +33,23 @@ use agent::FeudalAgent as Agent;
 use cgw::{GameSetting, Severity};
 use consts::*;
 use data::*;
+use std::process;
 use std::time::Duration;
 
 fn main() {
+    if let Some(s) = MATCHES.value_of("SIMULATE") {
+        // 敵の文字1つだけを受け付ける
+        let enem = s.bytes()
+            .next()
+            .filter(|_| s.len() == 1)
+            .map_or(Enemy::None, Enemy::from);
+        if enem == Enemy::None {
+            eprintln!("usage: --simulate H (one monster letter A-Z)");
+            process::exit(1);
+        }
+        damage::print_fight_report(enem, 20, 10000);
+        return;
+    }
     let max_loop = MATCHES
         .value_of("MAX_LOOP")
         .unwrap_or("100")
@@ -46,10 +60,22 @@ fn main() {
         .unwrap_or("100")
         .parse::<u64>()
         .expect("usage: --interval 100");
+    let default_geo = geometry();
+    let columns = MATCHES
+        .value_of("COLUMNS")
+        .map_or(default_geo.columns, |s| {
+            s.parse::<usize>().expect("usage: --columns 80")
+        });
+    let lines = MATCHES
+        .value_of("LINES")
+        .map_or(default_geo.lines, |s| {
+            s.parse::<usize>().expect("usage: --lines 24")
+        });
+    set_geometry(Geometry::new(columns, lines));
     let mut gs = GameSetting::new("rogue")
         .env("ROGUEUSER", "2ndAI")
-        .lines(LINES + 2)
-        .columns(COLUMNS)
+        .lines(lines)
+        .columns(columns)
         .debug_file("debug_cgw.txt")
         .debug_level(Severity::Debug)
         .max_loop(max_loop);
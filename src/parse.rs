@@ -68,12 +68,17 @@ Level:\D*
 pub struct MsgParse {
     rset: RegexSet,
     detect_enemy: Regex,
+    unseen: Regex,
+    cursed: Regex,
     detect_item: Regex,
     item_set: RegexSet,
     integer: Regex,
     potion: Regex,
     scroll: Regex,
     ring: Regex,
+    known_ring: Regex,
+    enchant: Regex,
+    level: i32,
 }
 
 impl MsgParse {
@@ -82,14 +87,14 @@ impl MsgParse {
         MsgParse {
             rset: RegexSet::new(&[
                 r"--More--",                     // 0
-                r"The .*n't",                    // 1
-                r"The .*? miss",                 // 2
-                r"The .*? injured",              // 3
-                r"The .*? hit",                  // 4
+                r"(The|It) .*n't",               // 1
+                r"(The .*?|It.*?) miss",         // 2
+                r"(The .*?|It.*?) injured",      // 3
+                r"(The .*?|It.*?) hit",          // 4
                 r"Which direction",              // 5
                 r"You scored",                   // 6
                 r"You have defeated",            // 7
-                r"You .*? miss",                 // 8
+                r"You (.*? )?miss",              // 8
                 r"You .*?n't",                   // 9
                 r"You now have ",                // 10
                 r"You found",                    // 11
@@ -104,8 +109,18 @@ impl MsgParse {
                 r"do you want to call",          // 20
                 r"not a valid item",             // 21
                 r"no way down",                  // 22
+                r"so cosmic",                    // 23
+                r"SO boring",                    // 24
+                r"has a map on it",              // 25
+                r"presence of magic",            // 26
+                r"smell food",                   // 27
+                r"That's a xeroc|nasty critter", // 28
+                r"You are now wielding",         // 29
+                r"presence of monsters",         // 30
             ]).unwrap(),
             detect_enemy: Regex::new(r"(?i)the.*?(?P<enemy>\w)").unwrap(),
+            unseen: Regex::new(r"(?i)\bit\b").unwrap(),
+            cursed: Regex::new(r"appears to be cursed").unwrap(),
             detect_item: Regex::new(
                 r"You now have (?P<num>a|\d*)[ |[\w^a]](?P<item>.*?)\((?P<id>\w)\)",
             ).unwrap(),
@@ -140,12 +155,52 @@ impl MsgParse {
             potion: Regex::new(r".*?(?P<name>.*?) potion").unwrap(),
             scroll: Regex::new(r".*'(?P<name>.*)'").unwrap(),
             ring: Regex::new(r".*?(?P<name>.*?) ring").unwrap(),
+            known_ring: Regex::new(r"ring of (?P<name>[\w ]*?)( \[(?P<num>[+-]\d+)\])?\s*$").unwrap(),
+            enchant: Regex::new(r"(?P<hit>[+-]\d+),(?P<dam>[+-]\d+)").unwrap(),
+            level: 1,
         }
     }
 
+    // 名前で決まらない敵はその階で出やすいものにする
+    pub fn set_level(&mut self, level: i32) {
+        self.level = level;
+    }
+
     fn enemy(&self, s: &str) -> Enemy {
+        // 見えない敵は"it"と呼ばれる
+        if self.unseen.is_match(s) {
+            return self.unseen_enemy();
+        }
         let cap = self.detect_enemy.captures(s).unwrap();
-        Enemy::from(cap["enemy"].as_bytes()[0])
+        let start = cap.name("enemy").unwrap().start();
+        let rest = s[start..].to_lowercase();
+        let all = Enemy::all();
+        if let Some(&e) = all.iter().find(|e| rest.starts_with(e.name())) {
+            return e;
+        }
+        // 知らない名前なら頭文字が合う敵から選ぶ 名前がなければ見えない敵とみなす
+        let head = match rest.bytes().next() {
+            Some(head) => head,
+            None => return self.unseen_enemy(),
+        };
+        let cands: Vec<_> = all.into_iter()
+            .filter(|e| e.name().as_bytes()[0] == head || *e == Enemy::from(head))
+            .collect();
+        likely_enemy(&cands, self.level)
+    }
+
+    // 見えない敵はまずPhantomを疑い、出てこない階なら目が見えない時の普通の敵とみなす
+    fn unseen_enemy(&self) -> Enemy {
+        let invisible: Vec<_> = appear_prob(self.level, false)
+            .into_iter()
+            .map(|(e, _)| e)
+            .filter(|e| e.has_attr(EnemyAttr::INVISIBLE))
+            .collect();
+        if invisible.is_empty() {
+            likely_enemy(&Enemy::all(), self.level)
+        } else {
+            likely_enemy(&invisible, self.level)
+        }
     }
 
     fn match_item(&self, s: &str) -> Item {
@@ -196,13 +251,21 @@ impl MsgParse {
             let cp = re.captures(&cap["item"]).unwrap();
             ret_item!(item, &cp["name"])
         };
-        if matches.is_empty() {
+        let mut res = if matches.is_empty() {
             ret_item!(Item::None)
         } else {
             match matches[0] {
                 0 => ret_with_n(Item::Potion, &self.potion),
                 1 => ret_with_n(Item::Scroll, &self.scroll),
-                2 => ret_with_n(Item::Ring, &self.ring),
+                // 正体の分かった指輪は"ring of dexterity [+2]"のように書かれる
+                2 => match self.known_ring.captures(&cap["item"]) {
+                    Some(cp) => {
+                        let mut ring = ret_item!(Item::Ring, &cp["name"]);
+                        ring.val = cp.name("num").map(|m| m.as_str().parse().unwrap());
+                        ring
+                    }
+                    None => ret_with_n(Item::Ring, &self.ring),
+                },
                 3 => ret_item!(Item::Food(Food::Ration)),
                 4 => ret_item!(Item::Food(Food::SlimeMold)),
                 5 => ret_item!(Item::Armor(Armor::Leather)),
@@ -226,7 +289,14 @@ impl MsgParse {
                 24 => ret_item!(Item::Amulet),
                 _ => ret_item!(Item::None),
             }
+        };
+        // 補正の分かった武器は"+1,+1 mace"のように書かれる
+        if let Item::Weapon(_) = res.typ {
+            if let Some(cp) = self.enchant.captures(&cap["item"]) {
+                res.plus = Some((cp["hit"].parse().unwrap(), cp["dam"].parse().unwrap()));
+            }
         }
+        res
     }
 
     fn to_int(&self, s: &str) -> u32 {
@@ -247,6 +317,16 @@ impl MsgParse {
         }
     }
 
+    // 投げた武器が外れても"The arrow misses ..."と出る
+    fn miss(&self, s: &str) -> GameMsg {
+        if self.item_set.is_match(s) {
+            let st = str::from_utf8(&s.as_bytes()[3..]).unwrap();
+            GameMsg::Missed(self.enemy(st))
+        } else {
+            GameMsg::NotInjured(self.enemy(s))
+        }
+    }
+
     pub fn parse(&self, s: &str) -> (GameMsg, bool) {
         let matches: BinaryHeap<_> = self.rset.matches(s).into_iter().collect();
         let mut more = false;
@@ -255,7 +335,8 @@ impl MsgParse {
         for m in matches {
             match m {
                 0 => more = true,
-                1 | 2 => res = NotInjured(self.enemy(s)),
+                1 => res = NotInjured(self.enemy(s)),
+                2 => res = self.miss(s),
                 3 => res = Injured(self.enemy(s)),
                 4 => res = self.hit(s),
                 5 => res = Direction,
@@ -275,9 +356,21 @@ impl MsgParse {
                 20 => res = CallIt,
                 21 => res = NotValid,
                 22 => res = NoStair,
+                23 => res = Hallucinate(true),
+                24 => res = Hallucinate(false),
+                25 => res = Detected(Detection::Map),
+                26 => res = Detected(Detection::Magic),
+                27 => res = Detected(Detection::Food),
+                28 => res = Mimic,
+                29 => res = Wielded,
+                30 => res = Detected(Detection::Monster),
                 _ => {}
             }
         }
+        // "You can't."で始まるので、敵への攻撃と間違えないよう後から見る
+        if self.cursed.is_match(s) {
+            res = Cursed;
+        }
         (res, more)
     }
 }
@@ -345,6 +438,19 @@ mod test {
             "You moved onto splint mail",
             "'w' is not a valid item--More--",
             "I see no way down",
+            "Oh wow!  Everything seems so cosmic!",
+            "Everything looks SO boring now.",
+            "oh, now this scroll has a map on it.",
+            "You sense the presence of magic on this level.--More--",
+            "Your nose tingles as you smell food.--More--",
+            "The venus flytrap hit you",
+            "The black unicorn misses you",
+            "wait!  That's a xeroc!--More--",
+            "It hits you",
+            "You miss it",
+            "You are now wielding a +1,+1 mace (c)",
+            "You begin to sense the presence of monsters.--More--",
+            "You can't.  It appears to be cursed",
         ];
         let answers = vec![
             (GameMsg::Injured(Enemy::Emu), false),
@@ -364,6 +470,7 @@ mod test {
                     num: 32,
                     typ: Item::Gold,
                     val: None,
+                    plus: None,
                 }),
                 false,
             ),
@@ -374,6 +481,7 @@ mod test {
                     num: 1,
                     typ: Item::Potion,
                     val: None,
+                    plus: None,
                 }),
                 false,
             ),
@@ -384,6 +492,7 @@ mod test {
                     num: 1,
                     typ: Item::Scroll,
                     val: None,
+                    plus: None,
                 }),
                 false,
             ),
@@ -394,6 +503,7 @@ mod test {
                     num: 2,
                     typ: Item::Scroll,
                     val: None,
+                    plus: None,
                 }),
                 true,
             ),
@@ -404,6 +514,7 @@ mod test {
                     num: 1,
                     typ: Item::Armor(Armor::Scale),
                     val: None,
+                    plus: None,
                 }),
                 false,
             ),
@@ -414,6 +525,7 @@ mod test {
                     num: 1,
                     typ: Item::Ring,
                     val: None,
+                    plus: None,
                 }),
                 false,
             ),
@@ -424,6 +536,7 @@ mod test {
                     num: 1,
                     typ: Item::Ring,
                     val: None,
+                    plus: None,
                 }),
                 false,
             ),
@@ -434,6 +547,7 @@ mod test {
                     num: 2,
                     typ: Item::Food(Food::Ration),
                     val: None,
+                    plus: None,
                 }),
                 true,
             ),
@@ -450,11 +564,44 @@ mod test {
             (GameMsg::MovedOnto(Item::Armor(Armor::Splint)), false),
             (GameMsg::NotValid, true),
             (GameMsg::NoStair, false),
+            (GameMsg::Hallucinate(true), false),
+            (GameMsg::Hallucinate(false), false),
+            (GameMsg::Detected(Detection::Map), false),
+            (GameMsg::Detected(Detection::Magic), true),
+            (GameMsg::Detected(Detection::Food), true),
+            (GameMsg::Injured(Enemy::Flytrap), false),
+            (GameMsg::NotInjured(Enemy::UrVile), false),
+            (GameMsg::Mimic, true),
+            (GameMsg::Injured(Enemy::Hobgoblin), false),
+            (GameMsg::Missed(Enemy::Hobgoblin), false),
+            (GameMsg::Wielded, false),
+            (GameMsg::Detected(Detection::Monster), true),
+            (GameMsg::Cursed, false),
         ];
-        let parser = MsgParse::new();
+        let mut parser = MsgParse::new();
         for (&msg, ans) in msgs.iter().zip(answers.iter()) {
             let parsed = parser.parse(msg);
             assert_eq!(*ans, parsed);
         }
+        // 知らない名前は頭文字と階層から決める
+        let unknown = "The vorpal bunny hit you";
+        parser.set_level(13);
+        assert_eq!(parser.parse(unknown).0, GameMsg::Injured(Enemy::Flytrap));
+        parser.set_level(26);
+        assert_eq!(parser.parse(unknown).0, GameMsg::Injured(Enemy::Vampire));
+        // Phantomが出る階の見えない敵はPhantom
+        parser.set_level(20);
+        assert_eq!(parser.parse("It misses you").0, GameMsg::NotInjured(Enemy::Phantom));
+        // 武器と指輪の補正値
+        let mut sword = ItemPack::new(b'h', "", 1, Item::Weapon(Weapon::LongSword));
+        sword.plus = Some((1, -2));
+        assert_eq!(parser.parse("You now have a +1,-2 long sword (h)").0, GameMsg::Item(sword));
+        let mut ring = ItemPack::new(b'g', "increase damage", 1, Item::Ring);
+        ring.val = Some(2);
+        let msg = "You now have a ring of increase damage [+2] (g)";
+        assert_eq!(parser.parse(msg).0, GameMsg::Item(ring));
+        // 外れた矢は敵の攻撃ではない
+        let msg = "The arrow misses the kestrel";
+        assert_eq!(parser.parse(msg).0, GameMsg::Missed(Enemy::Kestrel));
     }
 }
@@ -29,14 +29,15 @@ pub fn str_to_buf(s: &str) -> Vec<Vec<u8>> {
             continue;
         }
         let mut v = buf.as_bytes().to_owned();
-        while v.len() < COLUMNS {
+        while v.len() < geometry().columns {
             v.push(b' ');
         }
         res.push(v);
         buf.clear();
     }
-    while res.len() < LINES {
-        res.push(vec![b' '; COLUMNS]);
+    let geo = geometry();
+    while res.len() < geo.map_lines() {
+        res.push(vec![b' '; geo.columns]);
     }
     res
 }
@@ -45,6 +46,6 @@ pub fn str_to_buf(s: &str) -> Vec<Vec<u8>> {
 pub fn make_dangeon(s: &str) -> Dangeon {
     let mut res = Dangeon::default();
     let buf = str_to_buf(s);
-    res.merge(&buf);
+    res.merge(&buf, 0);
     res
 }